@@ -50,6 +50,8 @@ struct Crab {
     farts: usize,
     fart_timer: f32,
     custom: Option<String>,
+    /// Chat name color
+    color: Option<Rgba<f32>>,
     score: i32,
 }
 
@@ -82,6 +84,7 @@ impl Crab {
             farts: 0,
             fart_timer: 0.0,
             custom: None,
+            color: None,
         }
     }
     fn update(&mut self, screen_size: vec2<f32>, delta_time: f32) {
@@ -138,6 +141,7 @@ pub struct State {
     crabs: HashMap<String, Crab>,
     farticles: Vec<Farticle>,
     connection: Connection,
+    commands: Commands,
     bounce_points: Vec<vec2<f32>>,
     bouncy_game: bool,
     breakout: bool,
@@ -152,6 +156,8 @@ impl Feature for State {
     where
         Self: Sized,
     {
+        // Custom avatars can be changed from other overlays
+        connection.watch("");
         Self {
             disabled: false,
             geng: geng.clone(),
@@ -173,6 +179,52 @@ impl Feature for State {
                 v
             },
             connection,
+            commands: Commands::new()
+                .with(
+                    Command::new("toggle")
+                        .arg("feature", ArgKind::Word)
+                        .role(Role::Moderator)
+                        .description("Turn avatars on or off"),
+                )
+                .with(
+                    Command::new("setavatar")
+                        .arg("name", ArgKind::Word)
+                        .optional_arg("avatar", ArgKind::Word)
+                        .role(Role::Moderator)
+                        .description("Give someone a custom avatar, or take it away"),
+                )
+                .with(
+                    Command::new("bounce")
+                        .role(Role::Moderator)
+                        .description("Toggle the bouncy game"),
+                )
+                .with(
+                    Command::new("breakout")
+                        .optional_arg("size", ArgKind::Integer)
+                        .role(Role::Moderator)
+                        .description("Toggle breakout"),
+                )
+                .with(
+                    Command::new("jump")
+                        .optional_arg("angle", ArgKind::Integer)
+                        .description("Jump, optionally at an angle"),
+                )
+                .with(Command::new("fart").description("Fart").cooldown(Cooldown {
+                    global: 0.0,
+                    user: 10.0,
+                    reply: false,
+                }))
+                .with(
+                    Command::new("doublefart")
+                        .description("Fart twice")
+                        .cooldown(Cooldown {
+                            global: 0.0,
+                            user: 20.0,
+                            reply: false,
+                        }),
+                )
+                .with(Command::new("drop").description("Drop from the sky in the bouncy game"))
+                .with_cooldowns(&load_cooldowns(&geng, &path).await),
             assets: geng::asset::Load::load(geng.asset_manager(), &path, &default())
                 .await
                 .unwrap(),
@@ -339,7 +391,7 @@ impl Feature for State {
                 name,
                 vec2::splat(geng::TextAlign::CENTER),
                 mat3::translate(crab.pos + vec2(0.0, 1.0)) * mat3::scale_uniform(0.5),
-                Rgba::BLACK,
+                crab.color.unwrap_or(Rgba::BLACK),
                 0.08,
                 Rgba::WHITE,
             );
@@ -506,64 +558,87 @@ impl Feature for State {
             }
         }
     }
+    fn commands(&self) -> Vec<Command> {
+        self.commands.iter().cloned().collect()
+    }
     async fn handle(&mut self, message: &ServerMessage) {
+        if let Some((key, custom)) = self.connection.key_changed::<String>(message) {
+            for (name, crab) in &mut self.crabs {
+                if key_segment(name) == key {
+                    crab.custom = custom.clone();
+                }
+            }
+            return;
+        }
+        let command = self.commands.parse(message, &self.connection).await;
         if let ServerMessage::ChatMessage {
-            id: _,
             name,
             message,
+            user,
+            ..
         } = message
         {
-            let parts: Vec<&str> = message.split_whitespace().collect();
-            if name == "kuviman" {
-                if message == "!toggle avatars" {
-                    self.disabled = !self.disabled;
+            match command.as_ref() {
+                Some(command) if command.command == "toggle" => {
+                    if command.text("feature") == Some("avatars") {
+                        self.disabled = !self.disabled;
+                    }
                 }
-                if parts.first() == Some(&"!setavatar") && parts.len() == 3 {
-                    self.connection
-                        .set_key_value(&format!("avatars/{}", parts[1]), &parts[2]);
-                    if let Some(crab) = self.crabs.get_mut(parts[1]) {
-                        crab.custom = Some(parts[2].to_owned());
+                Some(command) if command.command == "setavatar" => {
+                    let name = command.text("name").unwrap();
+                    let key = key_segment(name);
+                    let avatar = command.text("avatar");
+                    match avatar {
+                        Some(avatar) => self.connection.set_key_value(&key, &avatar),
+                        None => {
+                            if let Err(e) = self.connection.delete_key(&key).await {
+                                log::error!("Failed to remove {name}'s avatar: {e:#}");
+                                return;
+                            }
+                        }
+                    }
+                    if let Some(crab) = self.crabs.get_mut(name) {
+                        crab.custom = avatar.map(|avatar| avatar.to_owned());
                     }
                     return;
                 }
-                if message == "!bounce" {
+                Some(command) if command.command == "bounce" => {
                     self.bouncy_game = !self.bouncy_game;
                     return;
                 }
-                if parts.first() == Some(&"!breakout") {
-                    if let Some(size) = parts.get(1) {
-                        if let Ok(size) = size.parse() {
-                            self.grid_size = size;
-                        }
+                Some(command) if command.command == "breakout" => {
+                    if let Some(size) = command.integer("size") {
+                        self.grid_size = size as i32;
                     }
                     self.breakout = !self.breakout;
                     self.bricks.clear();
                     self.win_timer = 0.0;
                     return;
                 }
+                _ => {}
             }
             let crab = self.crabs.entry(name.to_owned()).or_insert_with(Crab::new);
-            crab.custom = self
-                .connection
-                .get_key_value(&format!("avatars/{name}"))
-                .await;
-            if parts.first() == Some(&"!jump") {
-                let angle = parts.get(1).and_then(|s| s.parse().ok()).unwrap_or(0);
-                let angle = angle.clamp_abs(45);
-                crab.vel = vec2(0.0, if self.breakout { 30.0 } else { 10.0 })
-                    .rotate(Angle::from_radians(angle as f32 * f32::PI / 180.0));
-                return;
+            match self.connection.get_key_value(&key_segment(name)).await {
+                Ok(custom) => crab.custom = custom,
+                Err(e) => log::error!("Failed to get {name}'s avatar: {e:#}"),
             }
-            match message.trim() {
-                "!fart" => {
+            crab.color = user.color;
+            match command.as_ref().map(|command| command.command.as_str()) {
+                Some("jump") => {
+                    let command = command.as_ref().unwrap();
+                    let angle = command.integer("angle").unwrap_or(0).clamp(-45, 45);
+                    crab.vel = vec2(0.0, if self.breakout { 30.0 } else { 10.0 })
+                        .rotate(Angle::from_radians(angle as f32 * f32::PI / 180.0));
+                }
+                Some("fart") => {
                     crab.farts = 1;
                     crab.fart_timer = 0.0;
                 }
-                "!doublefart" => {
+                Some("doublefart") => {
                     crab.farts = 2;
                     crab.fart_timer = 0.0;
                 }
-                "!drop" if self.bouncy_game => {
+                Some("drop") if self.bouncy_game => {
                     crab.pos = vec2(
                         thread_rng().gen_range(-10.0..10.0),
                         self.camera.fov / 2.0 + 1.0,
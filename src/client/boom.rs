@@ -8,6 +8,8 @@ struct Assets {
 
 pub struct State {
     geng: Geng,
+    connection: Connection,
+    commands: Commands,
     assets: Assets,
     framebuffer_size: vec2<f32>,
     camera: geng::Camera2d,
@@ -21,11 +23,24 @@ impl Feature for State {
     where
         Self: Sized,
     {
+        let commands = Commands::new()
+            .with(
+                Command::new("boom")
+                    .description("Blow something up")
+                    .cooldown(Cooldown {
+                        global: 5.0,
+                        user: 30.0,
+                        reply: false,
+                    }),
+            )
+            .with_cooldowns(&load_cooldowns(&geng, &path).await);
         Self {
             assets: geng::asset::Load::load(geng.asset_manager(), &path, &default())
                 .await
                 .unwrap(),
             geng,
+            connection,
+            commands,
             framebuffer_size: vec2(1.0, 1.0),
             camera: geng::Camera2d {
                 center: vec2::ZERO,
@@ -62,9 +77,17 @@ impl Feature for State {
         );
     }
 
+    fn commands(&self) -> Vec<Command> {
+        self.commands.iter().cloned().collect()
+    }
+
     async fn handle(&mut self, message: &ServerMessage) {
-        let ServerMessage::ChatMessage { message, .. } = message else { return };
-        if message.trim() != "!boom" {
+        if self
+            .commands
+            .parse(message, &self.connection)
+            .await
+            .is_none()
+        {
             return;
         }
         self.time = 1.0;
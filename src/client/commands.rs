@@ -0,0 +1,311 @@
+use super::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    /// Single word
+    Word,
+    Integer,
+    /// Everything until the end of the message
+    Text,
+}
+
+#[derive(Debug, Clone)]
+pub struct Arg {
+    pub name: String,
+    pub kind: ArgKind,
+    pub optional: bool,
+}
+
+#[derive(Debug, Clone)]
+enum ArgValue {
+    Text(String),
+    Integer(i64),
+}
+
+/// How often a command can be used (in seconds), moderators are not limited
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct Cooldown {
+    /// Between uses by anyone
+    #[serde(default)]
+    pub global: f64,
+    /// Between uses by the same user
+    #[serde(default)]
+    pub user: f64,
+    /// Tell the user how long to wait instead of silently ignoring the command
+    #[serde(default)]
+    pub reply: bool,
+}
+
+/// Read `cooldowns.json` (command name to [Cooldown]) from the feature directory, if it is there
+pub async fn load_cooldowns(geng: &Geng, path: &std::path::Path) -> HashMap<String, Cooldown> {
+    let json: String = match geng::asset::Load::load(
+        geng.asset_manager(),
+        &path.join("cooldowns.json"),
+        &default(),
+    )
+    .await
+    {
+        Ok(json) => json,
+        Err(_) => return default(),
+    };
+    serde_json::from_str(&json).unwrap()
+}
+
+/// Chat command like `!jump [angle]`
+#[derive(Debug, Clone)]
+pub struct Command {
+    /// Without the `!`, always lowercase
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub args: Vec<Arg>,
+    /// Who is allowed to use it
+    pub role: Role,
+    pub description: String,
+    pub cooldown: Cooldown,
+}
+
+impl Command {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_lowercase(),
+            aliases: vec![],
+            args: vec![],
+            role: Role::Everyone,
+            description: String::new(),
+            cooldown: default(),
+        }
+    }
+    pub fn alias(mut self, alias: &str) -> Self {
+        self.aliases.push(alias.to_lowercase());
+        self
+    }
+    pub fn arg(mut self, name: &str, kind: ArgKind) -> Self {
+        self.args.push(Arg {
+            name: name.to_owned(),
+            kind,
+            optional: false,
+        });
+        self
+    }
+    pub fn optional_arg(mut self, name: &str, kind: ArgKind) -> Self {
+        self.args.push(Arg {
+            name: name.to_owned(),
+            kind,
+            optional: true,
+        });
+        self
+    }
+    pub fn role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = description.to_owned();
+        self
+    }
+    pub fn cooldown(mut self, cooldown: Cooldown) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+    fn matches(&self, name: &str) -> bool {
+        self.name == name || self.aliases.iter().any(|alias| alias == name)
+    }
+    /// Like `!setavatar <name> <avatar>`
+    pub fn usage(&self) -> String {
+        let mut usage = format!("!{}", self.name);
+        for arg in &self.args {
+            if arg.optional {
+                usage += &format!(" [{}]", arg.name);
+            } else {
+                usage += &format!(" <{}>", arg.name);
+            }
+        }
+        usage
+    }
+}
+
+/// Successfully parsed command from a chat message
+#[derive(Debug, Clone)]
+pub struct Invocation {
+    /// Name of the command (even if it was called using an alias)
+    pub command: String,
+    pub message_id: MessageId,
+    pub user: ChatUser,
+    args: HashMap<String, ArgValue>,
+}
+
+impl Invocation {
+    /// Value of a [ArgKind::Word] or [ArgKind::Text] argument
+    pub fn text(&self, arg: &str) -> Option<&str> {
+        match self.args.get(arg)? {
+            ArgValue::Text(value) => Some(value),
+            ArgValue::Integer(_) => None,
+        }
+    }
+    pub fn integer(&self, arg: &str) -> Option<i64> {
+        match self.args.get(arg)? {
+            ArgValue::Integer(value) => Some(*value),
+            ArgValue::Text(_) => None,
+        }
+    }
+    pub fn reply(&self, connection: &Connection, text: &str) {
+        connection.reply(text, &self.message_id);
+    }
+}
+
+/// Commands of a single feature
+#[derive(Default)]
+pub struct Commands {
+    commands: Vec<Command>,
+}
+
+impl Commands {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn add(&mut self, command: Command) {
+        self.commands.push(command);
+    }
+    pub fn with(mut self, command: Command) -> Self {
+        self.add(command);
+        self
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &Command> {
+        self.commands.iter()
+    }
+    /// Override cooldowns of the commands, like the ones from [load_cooldowns]
+    pub fn with_cooldowns(mut self, cooldowns: &HashMap<String, Cooldown>) -> Self {
+        for command in &mut self.commands {
+            if let Some(&cooldown) = cooldowns.get(&command.name) {
+                command.cooldown = cooldown;
+            }
+        }
+        self
+    }
+    /// Find a command in the chat message and parse its arguments.
+    /// Replies with the usage if arguments are wrong.
+    /// Messages that are not commands, commands that the user is not allowed to use
+    /// and commands on cooldown are ignored.
+    pub async fn parse(
+        &self,
+        message: &ServerMessage,
+        connection: &Connection,
+    ) -> Option<Invocation> {
+        let ServerMessage::ChatMessage {
+            id,
+            name,
+            message,
+            user,
+            timestamp,
+            ..
+        } = message
+        else {
+            return None;
+        };
+        let text = message.trim().strip_prefix('!')?;
+        let (command_name, mut rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let command = self
+            .commands
+            .iter()
+            .find(|command| command.matches(&command_name.to_lowercase()))?;
+        if !user.has_role(command.role) {
+            log::debug!("{name} is not allowed to use !{}", command.name);
+            return None;
+        }
+        let mut args = HashMap::new();
+        for arg in &command.args {
+            rest = rest.trim_start();
+            let value = match arg.kind {
+                ArgKind::Text => mem::take(&mut rest).trim_end(),
+                ArgKind::Word | ArgKind::Integer => {
+                    let (word, remaining) =
+                        rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                    rest = remaining;
+                    word
+                }
+            };
+            if value.is_empty() {
+                if arg.optional {
+                    continue;
+                }
+                connection.reply(&format!("Usage: {}", command.usage()), id);
+                return None;
+            }
+            let value = match arg.kind {
+                ArgKind::Word | ArgKind::Text => ArgValue::Text(value.to_owned()),
+                ArgKind::Integer => match value.parse() {
+                    Ok(value) => ArgValue::Integer(value),
+                    Err(_) => {
+                        connection.reply(
+                            &format!("Usage: {} ({} must be a number)", command.usage(), arg.name),
+                            id,
+                        );
+                        return None;
+                    }
+                },
+            };
+            args.insert(arg.name.clone(), value);
+        }
+        if !user.has_role(Role::Moderator) {
+            let wait = match cooldown_wait(command, user, *timestamp, connection).await {
+                Ok(wait) => wait,
+                Err(e) => {
+                    // Not knowing the last use, we better not let everyone spam it
+                    log::error!("Failed to check cooldown of !{}: {e:#}", command.name);
+                    return None;
+                }
+            };
+            if wait > 0.0 {
+                log::debug!("!{} is on cooldown for {name} for {wait:.0}s", command.name);
+                if command.cooldown.reply {
+                    connection.reply(
+                        &format!("!{} is on cooldown, wait {}s ⏳", command.name, wait.ceil()),
+                        id,
+                    );
+                }
+                return None;
+            }
+        }
+        Some(Invocation {
+            command: command.name.clone(),
+            message_id: id.clone(),
+            user: user.clone(),
+            args,
+        })
+    }
+}
+
+/// How long until the user can use the command again, marks it as used if that is now.
+/// Last uses are stored in the key value storage so restarting does not reset cooldowns,
+/// in a namespace of their own so they do not mix with the feature's keys.
+async fn cooldown_wait(
+    command: &Command,
+    user: &ChatUser,
+    timestamp: f64,
+    connection: &Connection,
+) -> anyhow::Result<f64> {
+    let feature = connection.namespace();
+    let connection = &connection.with_namespace("cooldowns");
+    let global_key = format!("{feature}/{}", command.name);
+    let user_key = format!("{feature}/{}/{}", command.name, user.login);
+    let mut wait: f64 = 0.0;
+    if command.cooldown.global > 0.0 {
+        if let Some(last) = connection.get_key_value::<f64>(&global_key).await? {
+            wait = wait.max(last + command.cooldown.global - timestamp);
+        }
+    }
+    if command.cooldown.user > 0.0 {
+        if let Some(last) = connection.get_key_value::<f64>(&user_key).await? {
+            wait = wait.max(last + command.cooldown.user - timestamp);
+        }
+    }
+    if wait <= 0.0 {
+        if command.cooldown.global > 0.0 {
+            connection.set_key_value(&global_key, &timestamp);
+        }
+        if command.cooldown.user > 0.0 {
+            connection.set_key_value(&user_key, &timestamp);
+        }
+    }
+    Ok(wait)
+}
@@ -14,6 +14,7 @@ pub struct State {
     time: f32,
     name: String,
     font_program: ugli::Program,
+    connection: Connection,
 }
 
 #[async_trait(?Send)]
@@ -35,6 +36,7 @@ impl Feature for State {
             geng,
             time: 10.0,
             name: "".to_owned(),
+            connection,
         }
     }
 
@@ -74,10 +76,13 @@ impl Feature for State {
     }
 
     async fn handle(&mut self, message: &ServerMessage) {
-        let ServerMessage::RewardRedemption { name, reward } = message else { return };
-        if reward != "Hello" {
+        let ServerMessage::RewardRedemption { id, name, key, .. } = message else { return };
+        if key.as_deref() != Some("hello") {
             return;
         }
+        // Nothing else to do, so it should not wait in the reward queue
+        self.connection
+            .update_redemption(id, RedemptionStatus::Fulfilled);
         self.assets.sound.choose(&mut thread_rng()).unwrap().play();
         self.time = 0.0;
         self.name = name.clone();
@@ -0,0 +1,111 @@
+use super::*;
+
+/// Answers `!commands` and `!help <command>` using the commands of all the other features
+pub struct State {
+    connection: Connection,
+    commands: Commands,
+    /// Commands of every feature, including our own
+    all_commands: Vec<Command>,
+}
+
+impl State {
+    pub fn new(connection: Connection, mut all_commands: Vec<Command>) -> Self {
+        let commands = Commands::new()
+            .with(
+                Command::new("commands")
+                    .description("List the commands you can use")
+                    .cooldown(Cooldown {
+                        global: 10.0,
+                        user: 0.0,
+                        reply: false,
+                    }),
+            )
+            .with(
+                Command::new("help")
+                    .arg("command", ArgKind::Word)
+                    .description("Explain a command"),
+            );
+        all_commands.extend(commands.iter().cloned());
+        all_commands.sort_by(|a, b| a.name.cmp(&b.name));
+        all_commands.dedup_by(|a, b| a.name == b.name);
+        Self {
+            connection,
+            commands,
+            all_commands,
+        }
+    }
+}
+
+fn role_text(role: Role) -> &'static str {
+    match role {
+        Role::Everyone => "everyone",
+        Role::Subscriber => "subscribers",
+        Role::Vip => "VIPs",
+        Role::Moderator => "moderators",
+        Role::Broadcaster => "the streamer",
+    }
+}
+
+#[async_trait(?Send)]
+impl Feature for State {
+    async fn load(_geng: Geng, _path: std::path::PathBuf, _connection: Connection) -> Self
+    where
+        Self: Sized,
+    {
+        unreachable!("Help is created after other features are loaded")
+    }
+    async fn update(&mut self, _delta_time: f32) {}
+    fn draw(&mut self, _framebuffer: &mut ugli::Framebuffer) {}
+    fn commands(&self) -> Vec<Command> {
+        self.commands.iter().cloned().collect()
+    }
+    async fn handle(&mut self, message: &ServerMessage) {
+        let Some(command) = self.commands.parse(message, &self.connection).await else { return };
+        let available = self
+            .all_commands
+            .iter()
+            .filter(|available| command.user.has_role(available.role));
+        match command.command.as_str() {
+            "commands" => {
+                let list: Vec<String> = available
+                    .map(|command| format!("!{}", command.name))
+                    .collect();
+                command.reply(
+                    &self.connection,
+                    &format!("📜 Commands: {}", list.join(", ")),
+                );
+            }
+            "help" => {
+                let name = command.text("command").unwrap();
+                let name = name.strip_prefix('!').unwrap_or(name).to_lowercase();
+                let mut available = available;
+                let Some(help) = available
+                    .find(|command| command.name == name || command.aliases.contains(&name))
+                else {
+                    command.reply(
+                        &self.connection,
+                        &format!("There is no !{name}, see !commands 🤔"),
+                    );
+                    return;
+                };
+                let mut text = help.usage();
+                if !help.description.is_empty() {
+                    text += &format!(" - {}", help.description);
+                }
+                if !help.aliases.is_empty() {
+                    let aliases: Vec<String> = help
+                        .aliases
+                        .iter()
+                        .map(|alias| format!("!{alias}"))
+                        .collect();
+                    text += &format!(" (also {})", aliases.join(", "));
+                }
+                if help.role != Role::Everyone {
+                    text += &format!(" [{} only]", role_text(help.role));
+                }
+                command.reply(&self.connection, &text);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
@@ -9,6 +9,8 @@ struct Assets {
 
 pub struct State {
     geng: Geng,
+    connection: Connection,
+    commands: Commands,
     assets: Assets,
     time: f32,
 }
@@ -19,11 +21,24 @@ impl Feature for State {
     where
         Self: Sized,
     {
+        let commands = Commands::new()
+            .with(
+                Command::new("jumpscare")
+                    .description("Scare the streamer")
+                    .cooldown(Cooldown {
+                        global: 60.0,
+                        user: 300.0,
+                        reply: true,
+                    }),
+            )
+            .with_cooldowns(&load_cooldowns(&geng, &path).await);
         Self {
             assets: geng::asset::Load::load(geng.asset_manager(), &path, &default())
                 .await
                 .unwrap(),
             geng,
+            connection,
+            commands,
             time: 0.0,
         }
     }
@@ -50,9 +65,17 @@ impl Feature for State {
         );
     }
 
+    fn commands(&self) -> Vec<Command> {
+        self.commands.iter().cloned().collect()
+    }
+
     async fn handle(&mut self, message: &ServerMessage) {
-        let ServerMessage::ChatMessage { message, .. } = message else { return };
-        if message.trim() != "!jumpscare" {
+        if self
+            .commands
+            .parse(message, &self.connection)
+            .await
+            .is_none()
+        {
             return;
         }
         self.assets.sound.play();
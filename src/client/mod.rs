@@ -6,12 +6,16 @@ mod font;
 mod avatars;
 mod boom;
 mod hello;
+mod help;
 mod jumpscare;
 mod raffle_royale;
 mod sound_commands;
 mod text_commands;
+
+mod commands;
 mod util;
 
+use commands::*;
 use util::*;
 
 #[async_trait(?Send)]
@@ -23,61 +27,575 @@ trait Feature: 'static {
     async fn handle_event(&mut self, event: geng::Event) {}
     fn draw(&mut self, framebuffer: &mut ugli::Framebuffer);
     async fn handle(&mut self, message: &ServerMessage);
+    /// Chat commands this feature reacts to, for !help
+    fn commands(&self) -> Vec<Command> {
+        vec![]
+    }
+}
+
+type RawConnection = geng::net::client::Connection<ServerMessage, ClientMessage>;
+
+/// Reconnect delay doubles after every failed attempt up to this value (in seconds)
+const MAX_RECONNECT_DELAY: f32 = 30.0;
+/// Server pings us every few seconds, so not hearing anything for this long means it is gone
+const SERVER_TIMEOUT: f32 = 30.0;
+
+/// How often to repeat [ClientMessage::Hello] until the server is ready (in seconds)
+const HELLO_INTERVAL: f32 = 1.0;
+
+enum ConnectionState {
+    /// Connected, but waiting for the server to be ready
+    Handshake {
+        connection: RawConnection,
+        hello_timer: f32,
+    },
+    Connected(RawConnection),
+    Connecting(Pin<Box<dyn Future<Output = anyhow::Result<RawConnection>>>>),
+    /// Waiting before the next attempt to connect
+    Reconnecting {
+        timer: f32,
+    },
+    /// Server speaks a different protocol, no point in reconnecting
+    Failed,
+}
+
+struct ConnectionInner {
+    addr: String,
+    /// Channels we get messages from, all of them if empty
+    channels: Vec<String>,
+    state: ConnectionState,
+    reconnect_delay: f32,
+    /// Time since we last heard from the server
+    silence: f32,
+    /// Messages sent while offline, delivered after reconnecting
+    outgoing: std::collections::VecDeque<ClientMessage>,
+    waiting_for_replies: HashMap<String, futures::channel::oneshot::Sender<ServerMessage>>,
+    /// Subscriptions to re-issue after reconnecting
+    watches: Vec<ClientMessage>,
+}
+
+impl ConnectionState {
+    fn connect(addr: &str) -> Self {
+        let addr = addr.to_owned();
+        Self::Connecting(async move { geng::net::client::connect(&addr).await }.boxed_local())
+    }
+}
+
+impl ConnectionInner {
+    fn send(&mut self, message: ClientMessage) {
+        match &mut self.state {
+            ConnectionState::Connected(connection) => connection.send(message),
+            ConnectionState::Handshake { connection, .. }
+                if matches!(message, ClientMessage::Pong) =>
+            {
+                connection.send(message)
+            }
+            _ => match message {
+                ClientMessage::Say { .. }
+                | ClientMessage::SetKeyValue { .. }
+                | ClientMessage::UpdateRedemption { .. } => {
+                    self.outgoing.push_back(message);
+                }
+                _ => log::debug!("Not connected, dropping {message:?}"),
+            },
+        }
+    }
+    fn connected(&mut self, connection: RawConnection) {
+        log::info!(
+            "Connected to {}, waiting for the server to be ready",
+            self.addr
+        );
+        self.state = ConnectionState::Handshake {
+            connection,
+            hello_timer: 0.0,
+        };
+        self.reconnect_delay = 1.0;
+        self.silence = 0.0;
+    }
+    fn welcome(&mut self, protocol_version: u32, ready: bool, capabilities: Vec<String>) {
+        if protocol_version != PROTOCOL_VERSION {
+            log::error!(
+                "Server at {} speaks protocol version {protocol_version}, \
+                but this overlay needs {PROTOCOL_VERSION}, update the one that is older",
+                self.addr,
+            );
+            self.state = ConnectionState::Failed;
+            return;
+        }
+        if !ready || !matches!(self.state, ConnectionState::Handshake { .. }) {
+            return;
+        }
+        let ConnectionState::Handshake { mut connection, .. } =
+            mem::replace(&mut self.state, ConnectionState::Failed)
+        else {
+            unreachable!()
+        };
+        log::info!("Server is ready, capabilities: {capabilities:?}");
+        for watch in &self.watches {
+            connection.send(watch.clone());
+        }
+        for message in self.outgoing.drain(..) {
+            connection.send(message);
+        }
+        self.state = ConnectionState::Connected(connection);
+    }
+    fn disconnected(&mut self, reason: &str) {
+        log::warn!(
+            "Connection to {} failed: {reason}, reconnecting in {}s",
+            self.addr,
+            self.reconnect_delay,
+        );
+        self.state = ConnectionState::Reconnecting {
+            timer: self.reconnect_delay,
+        };
+        self.reconnect_delay = (self.reconnect_delay * 2.0).min(MAX_RECONNECT_DELAY);
+        for (request_id, sender) in self.waiting_for_replies.drain() {
+            let _ = sender.send(ServerMessage::Error {
+                request_id: Some(request_id),
+                message: "Disconnected from the server".to_owned(),
+            });
+        }
+    }
 }
 
+/// Connection to the server that survives server restarts
 #[derive(Clone)]
 pub struct Connection {
-    inner: Arc<Mutex<geng::net::client::Connection<ServerMessage, ClientMessage>>>,
-    waiting_for_replies:
-        Arc<Mutex<HashMap<String, futures::channel::oneshot::Sender<ServerMessage>>>>,
+    inner: Arc<Mutex<ConnectionInner>>,
+    /// Key value storage namespace, every feature gets its own
+    namespace: Option<String>,
 }
 
 impl Connection {
-    fn new(connection: geng::net::client::Connection<ServerMessage, ClientMessage>) -> Self {
+    fn new(addr: &str, channels: &[String]) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(ConnectionInner {
+                addr: addr.to_owned(),
+                channels: channels.to_vec(),
+                state: ConnectionState::connect(addr),
+                reconnect_delay: 1.0,
+                silence: 0.0,
+                outgoing: default(),
+                waiting_for_replies: default(),
+                watches: vec![],
+            })),
+            namespace: None,
+        }
+    }
+    /// Drive (re)connection and return new messages that were not replies to requests
+    fn update(&self, delta_time: f32) -> Vec<ServerMessage> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let mut messages = Vec::new();
+        if let ConnectionState::Handshake {
+            connection,
+            hello_timer,
+        } = &mut inner.state
+        {
+            *hello_timer -= delta_time;
+            if *hello_timer <= 0.0 {
+                *hello_timer = HELLO_INTERVAL;
+                connection.send(ClientMessage::Hello {
+                    protocol_version: PROTOCOL_VERSION,
+                    channels: inner.channels.clone(),
+                });
+            }
+        }
+        match &mut inner.state {
+            ConnectionState::Handshake { connection, .. }
+            | ConnectionState::Connected(connection) => {
+                let mut error = None;
+                for message in connection.new_messages() {
+                    match message {
+                        Ok(message) => messages.push(message),
+                        Err(e) => {
+                            error = Some(e.to_string());
+                            break;
+                        }
+                    }
+                }
+                if messages.is_empty() {
+                    inner.silence += delta_time;
+                } else {
+                    inner.silence = 0.0;
+                }
+                if error.is_none() && inner.silence > SERVER_TIMEOUT {
+                    error = Some("server is not responding".to_owned());
+                }
+                if let Some(error) = error {
+                    inner.disconnected(&error);
+                }
+            }
+            ConnectionState::Connecting(future) => {
+                let poll = future.as_mut().poll(&mut std::task::Context::from_waker(
+                    futures::task::noop_waker_ref(),
+                ));
+                match poll {
+                    std::task::Poll::Ready(Ok(connection)) => inner.connected(connection),
+                    std::task::Poll::Ready(Err(e)) => inner.disconnected(&e.to_string()),
+                    std::task::Poll::Pending => {}
+                }
+            }
+            ConnectionState::Reconnecting { timer } => {
+                *timer -= delta_time;
+                if *timer <= 0.0 {
+                    inner.state = ConnectionState::connect(&inner.addr);
+                }
+            }
+            ConnectionState::Failed => {}
+        }
+        let mut result = Vec::new();
+        for message in messages {
+            if let Some(request_id) = message.request_id().map(|id| id.to_owned()) {
+                match inner.waiting_for_replies.remove(&request_id) {
+                    Some(sender) => {
+                        let _ = sender.send(message);
+                    }
+                    None => log::warn!("Got reply to unknown request {request_id:?}"),
+                }
+                continue;
+            }
+            match message {
+                ServerMessage::Ping => inner.send(ClientMessage::Pong),
+                ServerMessage::Welcome {
+                    protocol_version,
+                    ready,
+                    capabilities,
+                    ..
+                } => inner.welcome(protocol_version, ready, capabilities),
+                ServerMessage::Error { message, .. } => log::error!("Server error: {message}"),
+                message => result.push(message),
+            }
+        }
+        result
+    }
+    /// Same connection with key value operations scoped to the namespace
+    fn with_namespace(&self, namespace: &str) -> Self {
         Self {
-            inner: Arc::new(Mutex::new(connection)),
-            waiting_for_replies: default(),
+            namespace: Some(namespace.to_owned()),
+            ..self.clone()
         }
     }
+    fn namespace(&self) -> String {
+        self.namespace
+            .clone()
+            .expect("Key value storage used without a namespace")
+    }
     fn say(&self, text: &str) {
         self.inner.lock().unwrap().send(ClientMessage::Say {
             text: text.to_owned(),
             reply_to: None,
+            channel: None,
+        });
+    }
+    /// Say in a specific channel, like the one an event came from
+    fn say_in(&self, channel: &str, text: &str) {
+        self.inner.lock().unwrap().send(ClientMessage::Say {
+            text: text.to_owned(),
+            reply_to: None,
+            channel: Some(channel.to_owned()),
         });
     }
     fn reply(&self, text: &str, to: &MessageId) {
         self.inner.lock().unwrap().send(ClientMessage::Say {
             text: text.to_owned(),
             reply_to: Some(to.clone()),
+            channel: None,
         });
     }
-    async fn get_key_value<T: serde::de::DeserializeOwned>(&self, key: &str) -> Option<T> {
+    /// Mark a channel point redemption as done, or give the points back
+    fn update_redemption(&self, id: &str, status: RedemptionStatus) {
+        self.inner
+            .lock()
+            .unwrap()
+            .send(ClientMessage::UpdateRedemption {
+                id: id.to_owned(),
+                status,
+            });
+    }
+    /// Send a message with a new request id and wait for the reply to it.
+    /// Fails with [ServerMessage::Error] if we are not connected.
+    async fn request(&self, message: impl FnOnce(String) -> ClientMessage) -> ServerMessage {
         let request_id: String = thread_rng()
             .sample_iter(rand::distributions::Alphanumeric)
             .map(|c| c as char)
             .take(64)
             .collect();
-        let (sender, receiver) = futures::channel::oneshot::channel();
-        self.waiting_for_replies
-            .lock()
-            .unwrap()
-            .insert(request_id.clone(), sender);
-        self.inner.lock().unwrap().send(ClientMessage::GetKeyValue {
-            request_id: request_id.clone(),
-            key: format!("{key}.json"),
-        });
-        let ServerMessage::KeyValue { request_id: response_request_id, value } = receiver.await.unwrap() else {
-            unreachable!()
+        let receiver = {
+            let mut inner = self.inner.lock().unwrap();
+            let ConnectionState::Connected(connection) = &mut inner.state else {
+                return ServerMessage::Error {
+                    request_id: Some(request_id),
+                    message: "Not connected to the server".to_owned(),
+                };
+            };
+            connection.send(message(request_id.clone()));
+            let (sender, receiver) = futures::channel::oneshot::channel();
+            inner.waiting_for_replies.insert(request_id, sender);
+            receiver
         };
-        assert!(request_id == response_request_id);
-        value.map(|s| serde_json::from_str(&s).unwrap())
+        receiver.await.unwrap()
+    }
+    /// `Ok(None)` means there is no such key, errors (like not being connected) are not that
+    async fn get_key_value<T: serde::de::DeserializeOwned>(
+        &self,
+        key: &str,
+    ) -> anyhow::Result<Option<T>> {
+        self.get_raw_value(key)
+            .await?
+            .map(|s| serde_json::from_str(&s))
+            .transpose()
+            .with_context(|| format!("Unexpected value of {key:?}"))
+    }
+    /// Value as it is stored, not deserialized
+    async fn get_raw_value(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let namespace = self.namespace();
+        let reply = self
+            .request(|request_id| ClientMessage::GetKeyValue {
+                request_id,
+                namespace,
+                key: format!("{key}.json"),
+            })
+            .await;
+        match reply {
+            ServerMessage::KeyValue { value, .. } => Ok(value),
+            ServerMessage::Error { message, .. } => {
+                anyhow::bail!("Failed to get {key:?}: {message}")
+            }
+            _ => unreachable!(),
+        }
     }
     fn set_key_value<T: Serialize>(&self, key: &str, value: &T) {
         self.inner.lock().unwrap().send(ClientMessage::SetKeyValue {
+            namespace: self.namespace(),
             key: format!("{key}.json"),
             value: serde_json::to_string(value).unwrap(),
         });
     }
+    /// Overlay clients currently connected to the server
+    #[allow(dead_code)] // For features that need to know about other overlays
+    async fn list_clients(&self) -> anyhow::Result<Vec<ClientInfo>> {
+        match self
+            .request(|request_id| ClientMessage::ListClients { request_id })
+            .await
+        {
+            ServerMessage::Clients { clients, .. } => Ok(clients),
+            ServerMessage::Error { message, .. } => {
+                anyhow::bail!("Failed to list clients: {message}")
+            }
+            _ => unreachable!(),
+        }
+    }
+    /// Get [ServerMessage::KeyChanged] for keys starting with the prefix
+    fn watch(&self, prefix: &str) {
+        let message = ClientMessage::Watch {
+            namespace: self.namespace(),
+            prefix: prefix.to_owned(),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        inner.watches.push(message.clone());
+        inner.send(message);
+    }
+    /// If the message is a change of a key in our namespace, returns the key and the new value.
+    /// Values of another type (like someone else's keys under a watched prefix) are skipped.
+    fn key_changed<T: serde::de::DeserializeOwned>(
+        &self,
+        message: &ServerMessage,
+    ) -> Option<(String, Option<T>)> {
+        let ServerMessage::KeyChanged { namespace, key, value } = message else { return None };
+        if Some(namespace) != self.namespace.as_ref() {
+            return None;
+        }
+        let key = key.strip_suffix(".json")?;
+        let value = match value {
+            Some(s) => match serde_json::from_str(s) {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    log::error!("Unexpected value of {key:?}: {e}");
+                    return None;
+                }
+            },
+            None => None,
+        };
+        Some((key.to_owned(), value))
+    }
+    /// Keys starting with the prefix
+    #[allow(dead_code)] // Nothing lists its keys yet, but features are expected to
+    async fn list_keys(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let namespace = self.namespace();
+        let reply = self
+            .request(|request_id| ClientMessage::ListKeys {
+                request_id,
+                namespace,
+                prefix: prefix.to_owned(),
+            })
+            .await;
+        match reply {
+            ServerMessage::Keys { keys, .. } => Ok(keys
+                .into_iter()
+                .filter_map(|key| key.strip_suffix(".json").map(|key| key.to_owned()))
+                .collect()),
+            ServerMessage::Error { message, .. } => {
+                anyhow::bail!("Failed to list {prefix:?}: {message}")
+            }
+            _ => unreachable!(),
+        }
+    }
+    /// Returns whether the key existed
+    async fn delete_key(&self, key: &str) -> anyhow::Result<bool> {
+        let namespace = self.namespace();
+        let reply = self
+            .request(|request_id| ClientMessage::DeleteKey {
+                request_id,
+                namespace,
+                key: format!("{key}.json"),
+            })
+            .await;
+        match reply {
+            ServerMessage::KeyDeleted { existed, .. } => Ok(existed),
+            ServerMessage::Error { message, .. } => {
+                anyhow::bail!("Failed to delete {key:?}: {message}")
+            }
+            _ => unreachable!(),
+        }
+    }
+    /// Atomically add to a counter (missing counter starts at 0), returns the new value
+    async fn increment(&self, key: &str, delta: i64) -> anyhow::Result<i64> {
+        let namespace = self.namespace();
+        let reply = self
+            .request(|request_id| ClientMessage::Increment {
+                request_id,
+                namespace,
+                key: format!("{key}.json"),
+                delta,
+            })
+            .await;
+        match reply {
+            ServerMessage::Incremented { value, .. } => Ok(value),
+            ServerMessage::Error { message, .. } => {
+                anyhow::bail!("Failed to increment {key:?}: {message}")
+            }
+            _ => unreachable!(),
+        }
+    }
+    /// Atomically replace the stored text if it is currently `expected` (`None` meaning absent).
+    /// Returns the actual current text otherwise.
+    async fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        value: Option<&str>,
+    ) -> anyhow::Result<Result<(), Option<String>>> {
+        let namespace = self.namespace();
+        let reply = self
+            .request(|request_id| ClientMessage::CompareAndSwap {
+                request_id,
+                namespace,
+                key: format!("{key}.json"),
+                expected: expected.map(|value| value.to_owned()),
+                value: value.map(|value| value.to_owned()),
+            })
+            .await;
+        match reply {
+            ServerMessage::Swapped { success: true, .. } => Ok(Ok(())),
+            ServerMessage::Swapped { current, .. } => Ok(Err(current)),
+            ServerMessage::Error { message, .. } => {
+                anyhow::bail!("Failed to compare and swap {key:?}: {message}")
+            }
+            _ => unreachable!(),
+        }
+    }
+    /// Read-modify-write without races, retrying if someone else changed the value meanwhile.
+    /// Returns the new value, or `None` if it could not be updated.
+    async fn update_key_value<T: Serialize + serde::de::DeserializeOwned>(
+        &self,
+        key: &str,
+        f: impl Fn(Option<&T>) -> T,
+    ) -> Option<T> {
+        const MAX_ATTEMPTS: usize = 10;
+        // The stored text is sent back as is, serializing the value again may give different text
+        let mut current = match self.get_raw_value(key).await {
+            Ok(current) => current,
+            Err(e) => {
+                log::error!("Failed to update {key:?}: {e:#}");
+                return None;
+            }
+        };
+        for _ in 0..MAX_ATTEMPTS {
+            let value = match current
+                .as_deref()
+                .map(serde_json::from_str::<T>)
+                .transpose()
+            {
+                Ok(value) => value,
+                Err(e) => {
+                    log::error!("Failed to update {key:?}, unexpected value: {e}");
+                    return None;
+                }
+            };
+            let new = f(value.as_ref());
+            let new_text = serde_json::to_string(&new).unwrap();
+            match self
+                .compare_and_swap(key, current.as_deref(), Some(&new_text))
+                .await
+            {
+                Ok(Ok(())) => return Some(new),
+                Ok(Err(actual)) => current = actual,
+                Err(e) => {
+                    log::error!("Failed to update {key:?}: {e:#}");
+                    return None;
+                }
+            }
+        }
+        log::error!("Failed to update {key:?}, too much contention");
+        None
+    }
+}
+
+/// Moderation for features like a link filter
+#[allow(dead_code)] // No feature moderates chat yet
+impl Connection {
+    /// Moderation requests only tell whether they succeeded
+    async fn moderate(&self, message: impl FnOnce(String) -> ClientMessage) -> anyhow::Result<()> {
+        match self.request(message).await {
+            ServerMessage::Moderated { .. } => Ok(()),
+            ServerMessage::Error { message, .. } => anyhow::bail!(message),
+            _ => unreachable!(),
+        }
+    }
+    async fn delete_message(&self, id: &MessageId) -> anyhow::Result<()> {
+        self.moderate(|request_id| ClientMessage::DeleteMessage {
+            request_id,
+            id: id.clone(),
+        })
+        .await
+    }
+    /// Time out the user in the channel for `duration` seconds
+    async fn timeout(
+        &self,
+        channel: &str,
+        login: &str,
+        duration: u64,
+        reason: &str,
+    ) -> anyhow::Result<()> {
+        self.moderate(|request_id| ClientMessage::Timeout {
+            request_id,
+            channel: channel.to_owned(),
+            login: login.to_owned(),
+            duration,
+            reason: reason.to_owned(),
+        })
+        .await
+    }
+    async fn ban(&self, channel: &str, login: &str, reason: &str) -> anyhow::Result<()> {
+        self.moderate(|request_id| ClientMessage::Ban {
+            request_id,
+            channel: channel.to_owned(),
+            login: login.to_owned(),
+            reason: reason.to_owned(),
+        })
+        .await
+    }
 }
 
 struct SyncFeature {
@@ -163,25 +681,75 @@ impl Overlay {
     pub fn new(geng: &Geng, connection: Connection, features: Vec<Box<dyn Feature>>) -> Self {
         let (sender, receiver) = std::sync::mpsc::channel();
         #[cfg(not(target_arch = "wasm32"))]
-        std::thread::spawn(move || loop {
-            let mut line = String::new();
-            std::io::stdin().read_line(&mut line).unwrap();
-            let line = line.trim();
-            let message = if let Some(reward) = line.strip_prefix("!redeem") {
-                ServerMessage::RewardRedemption {
-                    name: "kuviman".to_owned(),
-                    reward: reward.trim().to_owned(),
-                }
-            } else {
-                ServerMessage::ChatMessage {
-                    // TODO: rig it so badcop always wins
-                    id: MessageId("<fake id>".to_owned()),
-                    name: "kuviman".to_owned(),
-                    message: line.to_owned(),
+        std::thread::spawn({
+            // Fake messages pretend to come from the first channel we listen to
+            let channel = connection.inner.lock().unwrap().channels.first().cloned();
+            let channel = channel.unwrap_or_else(|| "kuviman".to_owned());
+            let fake_id = {
+                let channel = channel.clone();
+                move || MessageId {
+                    channel: channel.clone(),
+                    id: "<fake id>".to_owned(),
                 }
             };
-            if sender.send(message).is_err() {
-                break;
+            move || loop {
+                let mut line = String::new();
+                std::io::stdin().read_line(&mut line).unwrap();
+                let line = line.trim();
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs_f64();
+                let message = if let Some(reward) = line.strip_prefix("!redeem") {
+                    ServerMessage::RewardRedemption {
+                        channel: channel.clone(),
+                        id: "<fake id>".to_owned(),
+                        name: "kuviman".to_owned(),
+                        reward: reward.trim().to_owned(),
+                        // Keys are the titles in snake case, like `raffle_royale_level_up`
+                        key: Some(reward.trim().to_lowercase().replace(' ', "_")),
+                        reward_id: String::new(),
+                        cost: 0,
+                        input: None,
+                    }
+                } else if let Some((name, text)) = line
+                    .strip_prefix("!as")
+                    .and_then(|rest| rest.trim().split_once(' '))
+                {
+                    ServerMessage::ChatMessage {
+                        channel: channel.clone(),
+                        id: fake_id(),
+                        name: name.to_owned(),
+                        message: text.trim().to_owned(),
+                        user: ChatUser::from_name(name),
+                        emotes: vec![],
+                        bits: None,
+                        first_message: false,
+                        reply_parent: None,
+                        timestamp: now,
+                    }
+                } else {
+                    ServerMessage::ChatMessage {
+                        channel: channel.clone(),
+                        // TODO: rig it so badcop always wins
+                        id: fake_id(),
+                        name: "kuviman".to_owned(),
+                        message: line.to_owned(),
+                        // Whoever has access to the terminal is in charge
+                        user: ChatUser {
+                            role: Role::Broadcaster,
+                            ..ChatUser::from_name("kuviman")
+                        },
+                        emotes: vec![],
+                        bits: None,
+                        first_message: false,
+                        reply_parent: None,
+                        timestamp: now,
+                    }
+                };
+                if sender.send(message).is_err() {
+                    break;
+                }
             }
         });
         Self {
@@ -197,25 +765,11 @@ impl geng::State for Overlay {
         for feature in &mut self.features {
             feature.update(delta_time as f32);
         }
-        let mut new_messages = Vec::new();
-        for message in self.connection.inner.lock().unwrap().new_messages() {
-            new_messages.push(message.unwrap());
-        }
+        let mut new_messages = self.connection.update(delta_time as f32);
         for message in self.receiver.try_iter() {
             new_messages.push(message);
         }
         for message in new_messages {
-            if let ServerMessage::KeyValue { request_id, value } = &message {
-                self.connection
-                    .waiting_for_replies
-                    .lock()
-                    .unwrap()
-                    .remove(request_id)
-                    .unwrap()
-                    .send(message)
-                    .unwrap();
-                continue;
-            }
             log::info!("{:?}", message);
             for feature in &mut self.features {
                 feature.handle(&message);
@@ -244,13 +798,14 @@ macro_rules! load_features {
         let geng = $geng;
         let connection = $connection;
         vec![
-            $(load_feature::<$feature::State>(&geng, run_dir().join("assets").join(stringify!($feature)), connection.clone()),)*
+            $(load_feature::<$feature::State>(&geng, run_dir().join("assets").join(stringify!($feature)), connection.with_namespace(stringify!($feature))),)*
         ]
     }}
 }
 
-pub fn run(geng_args: &geng::CliArgs, addr: &str) {
+pub fn run(geng_args: &geng::CliArgs, addr: &str, channels: &[String]) {
     let addr = addr.to_owned();
+    let channels = channels.to_vec();
     Geng::run_with(
         &{
             let mut options = geng::ContextOptions {
@@ -263,9 +818,7 @@ pub fn run(geng_args: &geng::CliArgs, addr: &str) {
         },
         |geng| {
             async move {
-                let addr = addr.to_owned();
-                let connection = geng::net::client::connect(&addr).await.unwrap();
-                let connection = Connection::new(connection);
+                let connection = Connection::new(&addr, &channels);
 
                 fn load_feature<T: Feature>(
                     geng: &Geng,
@@ -286,7 +839,7 @@ pub fn run(geng_args: &geng::CliArgs, addr: &str) {
                     .unwrap()
                     .unwrap();
 
-                let features = future::join_all(load_features![
+                let mut features = future::join_all(load_features![
                     geng: &geng,
                     connection: connection.clone(),
                     avatars,
@@ -298,6 +851,14 @@ pub fn run(geng_args: &geng::CliArgs, addr: &str) {
                     text_commands,
                 ])
                 .await;
+                let all_commands = features
+                    .iter()
+                    .flat_map(|feature| feature.commands())
+                    .collect();
+                features.push(Box::new(help::State::new(
+                    connection.with_namespace("help"),
+                    all_commands,
+                )));
                 geng.clone()
                     .run_state(Overlay::new(&geng, connection, features))
                     .await;
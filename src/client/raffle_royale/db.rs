@@ -1,5 +1,10 @@
 use super::*;
 
+/// Key of the user's value, names are display names so they need escaping
+fn key(name: &str, value: &str) -> String {
+    format!("{}/{value}", key_segment(name))
+}
+
 pub struct Db {
     connection: Connection,
 }
@@ -9,50 +14,82 @@ impl Db {
         Self { connection }
     }
 
-    pub async fn find_level(&self, name: &str) -> usize {
-        self.connection
-            .get_key_value(&format!("raffle_royale/{name}/level"))
-            .await
-            .unwrap_or(1)
+    pub async fn find_level(&self, name: &str) -> anyhow::Result<usize> {
+        Ok(self
+            .connection
+            .get_key_value(&key(name, "level"))
+            .await?
+            .unwrap_or(1))
     }
 
-    pub fn set_level(&self, name: &str, level: usize) {
-        self.connection
-            .set_key_value(&format!("raffle_royale/{name}/level"), &level)
+    /// Returns the new level, or `None` if the levels could not be added
+    pub async fn add_levels(&self, name: &str, levels: usize) -> Option<usize> {
+        let key = key(name, "level");
+        let level = async {
+            let mut level = self.connection.increment(&key, levels as i64).await?;
+            if level == levels as i64 {
+                // Nothing was stored, which means level 1 and not 0
+                level = self.connection.increment(&key, 1).await?;
+            }
+            anyhow::Ok(level as usize)
+        }
+        .await;
+        match level {
+            Ok(level) => Some(level),
+            Err(e) => {
+                log::error!("Failed to add levels to {name}: {e:#}");
+                None
+            }
+        }
+    }
+
+    pub async fn find_skin(&self, name: &str) -> anyhow::Result<Option<Skin>> {
+        self.connection.get_key_value(&key(name, "skin")).await
     }
 
-    pub async fn find_skin(&self, name: &str) -> Option<Skin> {
+    /// Change the skin (`new` one if there is none) without losing changes made meanwhile.
+    /// Returns `None` if it could not be changed.
+    pub async fn update_skin(
+        &self,
+        name: &str,
+        new: impl Fn() -> Skin,
+        f: impl Fn(&mut Skin),
+    ) -> Option<Skin> {
         self.connection
-            .get_key_value(&format!("raffle_royale/{name}/skin"))
+            .update_key_value(&key(name, "skin"), |skin: Option<&Skin>| {
+                let mut skin = skin.cloned().unwrap_or_else(&new);
+                f(&mut skin);
+                skin
+            })
             .await
     }
 
     pub fn set_skin(&self, name: &str, skin: &Skin) {
-        self.connection
-            .set_key_value(&format!("raffle_royale/{name}/skin"), skin)
+        self.connection.set_key_value(&key(name, "skin"), skin)
     }
 
-    pub async fn find_game_link(&self, name: &str) -> Option<String> {
-        self.connection
-            .get_key_value::<Option<String>>(&format!("raffle_royale/{name}/game_link"))
-            .await
-            .flatten()
+    pub async fn find_game_link(&self, name: &str) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .connection
+            .get_key_value::<Option<String>>(&key(name, "game_link"))
+            .await?
+            .flatten())
     }
 
     pub fn set_game_link(&self, name: &str, url: Option<&str>) {
-        self.connection
-            .set_key_value(&format!("raffle_royale/{name}/game_link"), &url)
+        self.connection.set_key_value(&key(name, "game_link"), &url)
     }
 
-    pub async fn game_played(&self, name: &str) -> bool {
-        self.connection
-            .get_key_value(&format!("raffle_royale/{name}/game_played"))
-            .await
-            .unwrap_or(false)
+    pub async fn game_played(&self, name: &str) -> anyhow::Result<bool> {
+        Ok(self
+            .connection
+            .get_key_value(&key(name, "game_played"))
+            .await?
+            .unwrap_or(false))
     }
 
     pub fn set_game_played(&self, name: &str, played: bool) {
         self.connection
-            .set_key_value(&format!("raffle_royale/{name}/game_played"), &played);
+            .set_key_value(&key(name, "game_played"), &played);
     }
 }
@@ -61,6 +61,7 @@ enum RaffleMode {
 
 pub struct State {
     connection: Connection,
+    commands: Commands,
     // opt: Opt,
     geng: Geng,
     assets: Rc<Assets>,
@@ -142,6 +143,7 @@ impl State {
         Self {
             db: Db::new(connection.clone()),
             connection,
+            commands: ttv_commands::commands(),
             volume: assets.constants.volume,
             // config,
             // opt,
@@ -221,9 +223,8 @@ impl State {
 
     async fn levelup_all(&self) {
         for guy in &self.guys {
-            let current_level = self.db.find_level(&guy.name).await;
             if !guy.should_never_win {
-                self.db.set_level(&guy.name, current_level + 1);
+                self.db.add_levels(&guy.name, 1).await;
             }
         }
     }
@@ -250,19 +251,31 @@ impl State {
         self.raffle_mode = mode;
     }
 
-    async fn find_skin(&self, name: &str, insert_if_absent: bool) -> Skin {
-        if let Some(skin) = self.db.find_skin(name).await {
-            return skin;
+    /// Only a skin that is really absent gets replaced, not one we failed to read
+    async fn find_skin(&self, name: &str, insert_if_absent: bool) -> anyhow::Result<Skin> {
+        if let Some(skin) = self.db.find_skin(name).await? {
+            return Ok(skin);
         }
+        let skin = self.new_skin(name);
+        if insert_if_absent {
+            self.db.set_skin(name, &skin);
+        }
+        Ok(skin)
+    }
+
+    fn new_skin(&self, name: &str) -> Skin {
         let mut skin = Skin::random(&self.assets);
         if let Some(custom) = self.assets.guy.custom_map.get(name) {
             skin.custom = Some(custom.to_owned());
         }
-        if insert_if_absent {
-            self.db.set_skin(name, &skin);
-        }
         skin
     }
+
+    /// Reading what we know about someone failed, better do nothing than use defaults
+    fn storage_error(&self, command: &Invocation, e: anyhow::Error) {
+        log::error!("Failed to access raffle royale data: {e:#}");
+        command.reply(&self.connection, "Something went wrong, try again later 😕");
+    }
     async fn update_impl(&mut self, delta_time: f32) {
         if self.geng.window().is_key_pressed(geng::Key::PageUp) {
             self.volume = (self.volume + delta_time as f64).min(1.0);
@@ -313,37 +326,46 @@ impl State {
                                     message: format!("Winner is {} 🎉", winner.name),
                                 });
                             }
-                            RaffleMode::Ld => match self.db.find_game_link(&winner.name).await {
-                                Some(game_link) => {
-                                    if self.db.game_played(&winner.name).await {
-                                        self.delayed_messages.push(DelayedMessage {
-                                        time: self.time + 5.0,
-                                        message: format!(
-                                            "Winner is {} 🎉 Your game ({}) was already played, please stop cheating?? 👀", 
-                                            winner.name, game_link
-                                        ),
-                                    });
-                                    } else {
+                            RaffleMode::Ld => {
+                                let game = async {
+                                    let Some(game_link) =
+                                        self.db.find_game_link(&winner.name).await?
+                                    else {
+                                        return anyhow::Ok(None);
+                                    };
+                                    let played = self.db.game_played(&winner.name).await?;
+                                    Ok(Some((game_link, played)))
+                                }
+                                .await;
+                                let message = match game {
+                                    Ok(Some((game_link, true))) => format!(
+                                        "Winner is {} 🎉 Your game ({}) was already played, please stop cheating?? 👀",
+                                        winner.name, game_link
+                                    ),
+                                    Ok(Some((game_link, false))) => {
                                         self.db.set_game_played(&winner.name, true);
-                                        self.delayed_messages.push(DelayedMessage {
-                                            time: self.time + 5.0,
-                                            message: format!(
-                                                "Winner is {} 🎉 Now we play {} 👏",
-                                                winner.name, game_link
-                                            ),
-                                        });
+                                        format!(
+                                            "Winner is {} 🎉 Now we play {} 👏",
+                                            winner.name, game_link
+                                        )
                                     }
-                                }
-                                None => {
-                                    self.delayed_messages.push(DelayedMessage {
-                                        time: self.time + 5.0,
-                                        message: format!(
-                                            "Winner is {} 🎉 No game was submitted? 😔",
+                                    Ok(None) => format!(
+                                        "Winner is {} 🎉 No game was submitted? 😔",
+                                        winner.name
+                                    ),
+                                    Err(e) => {
+                                        log::error!(
+                                            "Failed to get {}'s game: {e:#}",
                                             winner.name
-                                        ),
-                                    });
-                                }
-                            },
+                                        );
+                                        format!("Winner is {} 🎉", winner.name)
+                                    }
+                                };
+                                self.delayed_messages.push(DelayedMessage {
+                                    time: self.time + 5.0,
+                                    message,
+                                });
+                            }
                         }
                     }
                     self.winning_screen = true;
@@ -469,15 +491,19 @@ impl Feature for State {
             }
             geng::Event::KeyPress { key } => match key {
                 geng::Key::S => {
-                    self.spawn_guy(
-                        thread_rng()
-                            .sample_iter(rand::distributions::Alphanumeric)
-                            .map(|c| c as char)
-                            .take(thread_rng().gen_range(5..=15))
-                            .collect(),
-                        true,
-                    )
-                    .await;
+                    let result = self
+                        .spawn_guy(
+                            thread_rng()
+                                .sample_iter(rand::distributions::Alphanumeric)
+                                .map(|c| c as char)
+                                .take(thread_rng().gen_range(5..=15))
+                                .collect(),
+                            true,
+                        )
+                        .await;
+                    if let Err(e) = result {
+                        log::error!("Failed to spawn a random guy: {e:#}");
+                    }
                 }
                 geng::Key::Space => {
                     if self.idle {
@@ -511,7 +537,14 @@ impl Feature for State {
                 .await
                 .unwrap();
         assets.process();
-        Self::new(&geng, &Rc::new(assets), connection)
+        let mut state = Self::new(&geng, &Rc::new(assets), connection);
+        state.commands = mem::take(&mut state.commands)
+            .with_cooldowns(&load_cooldowns(&geng, &assets_path).await);
+        state
+    }
+
+    fn commands(&self) -> Vec<Command> {
+        self.commands.iter().cloned().collect()
     }
 
     async fn handle(&mut self, message: &ServerMessage) {
@@ -1,19 +1,22 @@
 use super::*;
 
 impl State {
-    pub async fn spawn_guy(&mut self, name: String, random: bool) {
-        let level = self.db.find_level(&name).await;
+    /// Fails without spawning if we could not get what we know about the guy
+    pub async fn spawn_guy(&mut self, name: String, random: bool) -> anyhow::Result<()> {
+        let level = self.db.find_level(&name).await?;
         let health = self.assets.constants.initial_health
             + (level.max(1) - 1) * self.assets.constants.extra_health_per_level;
+        let should_never_win = random
+            || (self.raffle_mode == RaffleMode::Ld
+                && (self.db.game_played(&name).await?
+                    || self.db.find_game_link(&name).await?.is_none()));
+        let skin = self.find_skin(&name, !random).await?;
         let id = self.next_id;
         self.next_id += 1;
         self.guys.insert(Guy {
             id,
-            should_never_win: random
-                || (self.raffle_mode == RaffleMode::Ld
-                    && (self.db.game_played(&name).await
-                        || self.db.find_game_link(&name).await.is_none())),
-            skin: self.find_skin(&name, !random).await,
+            should_never_win,
+            skin,
             name,
             position: std::iter::from_fn(|| {
                 Some(
@@ -70,5 +73,6 @@ impl State {
             .effect();
         sound_effect.set_volume(self.volume);
         sound_effect.play();
+        Ok(())
     }
 }
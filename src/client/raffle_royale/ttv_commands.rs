@@ -1,191 +1,220 @@
 use super::*;
 
+pub fn commands() -> Commands {
+    let skin_part = |part: &str| {
+        Command::new(part)
+            .optional_arg(part, ArgKind::Word)
+            .description(&format!("Change the {part} of your guy"))
+    };
+    Commands::new()
+        .with(
+            Command::new("submit")
+                .arg("url", ArgKind::Text)
+                .description("Submit your game to be played"),
+        )
+        .with(skin_part("hat"))
+        .with(skin_part("face"))
+        .with(skin_part("robe"))
+        .with(skin_part("beard"))
+        .with(
+            Command::new("skin")
+                .optional_arg("random", ArgKind::Word)
+                .description("Show your skin or get a random one"),
+        )
+        .with(
+            Command::new("lvl")
+                .alias("level")
+                .description("Show your level"),
+        )
+        .with(
+            Command::new("setcustomskin")
+                .arg("name", ArgKind::Word)
+                .arg("skin", ArgKind::Word)
+                .role(Role::Moderator)
+                .description("Give someone a custom skin"),
+        )
+        .with(
+            Command::new("raffle")
+                .optional_arg("keyword", ArgKind::Text)
+                .role(Role::Moderator)
+                .description("Open a raffle, start or close it"),
+        )
+        .with(
+            Command::new("curse")
+                .arg("name", ArgKind::Word)
+                .role(Role::Moderator)
+                .description("Make someone never win"),
+        )
+        .with(
+            Command::new("bless")
+                .arg("name", ArgKind::Word)
+                .role(Role::Moderator)
+                .description("Give someone extra health"),
+        )
+        .with(
+            Command::new("spawn")
+                .arg("names", ArgKind::Text)
+                .role(Role::Moderator)
+                .description("Add guys to the raffle"),
+        )
+}
+
 impl State {
     pub async fn handle_message(&mut self, message: ServerMessage) {
         match message {
             ServerMessage::ChatMessage {
-                id: message_id,
-                name,
-                message,
+                id: ref message_id,
+                ref name,
+                message: ref message_text,
+                ..
             } => {
-                let mut name = name.as_str();
-                let mut message_text = message.as_str();
-                if name == "kuviman" {
-                    if let Some(text) = message_text.strip_prefix("!as") {
-                        if let Some((as_name, text)) = text.trim().split_once(' ') {
-                            name = as_name.trim();
-                            message_text = text.trim();
-                        }
-                    }
-                }
-                if let Some(url) = message_text.strip_prefix("!submit") {
-                    let url = url.trim();
-                    if url.is_empty() {
-                        self.connection
-                            .reply("Submit using !submit <url> 🔗", &message_id);
-                    } else if self.db.game_played(name).await {
-                        self.connection
-                            .reply("We have already played your game 😕", &message_id);
-                    } else if self.db.find_game_link(name).await.is_some() {
+                let name = name.as_str();
+                if message_text
+                    .trim()
+                    .eq_ignore_ascii_case(&format!("!{}", self.raffle_keyword))
+                {
+                    if self.idle {
                         self.connection
-                            .reply("You have already submitted a game tho 😕", &message_id);
-                    } else {
-                        self.db.set_game_link(name, Some(url));
-
-                        let mut text = "Submission successful 👌".to_owned();
-                        if let Some(guy) = self.guys.iter_mut().find(|guy| guy.name == name) {
-                            if guy.should_never_win {
-                                guy.should_never_win = false;
-                                text += " Your curse has been reversed";
+                            .reply("You are either too late or too early 😊", message_id);
+                    } else if !self.process_battle {
+                        if self.guys.iter().any(|guy| guy.name == name) {
+                            self.connection.reply("No cheating allowed 🚫", message_id);
+                        } else {
+                            if let Err(e) = self.spawn_guy(name.to_owned(), false).await {
+                                log::error!("Failed to spawn {name}: {e:#}");
+                                self.connection
+                                    .reply("Something went wrong, try again 😕", message_id);
+                            } else if self.raffle_mode == RaffleMode::Ld
+                                && matches!(self.db.find_game_link(name).await, Ok(None))
+                            {
+                                self.connection.reply("You didn't !submit a game so you are cursed. Submit to reverse it ⏳", message_id);
                             }
                         }
-                        self.connection.reply(&text, &message_id);
-                    }
-                }
-                if let Some(hat) = message_text.strip_prefix("!hat") {
-                    let hat = hat.trim();
-                    if self.assets.guy.hat.contains_key(hat) {
-                        let mut skin = self.find_skin(name, false).await;
-                        skin.hat = hat.to_owned();
-                        self.db.set_skin(name, &skin);
-                        if let Some(guy) = self.guys.iter_mut().find(|guy| guy.name == name) {
-                            guy.skin = skin;
-                        }
                     } else {
                         self.connection.reply(
-                            &format!(
-                                "⚙️ Hat options: {}",
-                                self.assets
-                                    .guy
-                                    .hat
-                                    .keys()
-                                    .map(|s| s.as_str())
-                                    .collect::<Vec<_>>()
-                                    .join(", ")
-                            ),
-                            &message_id,
+                            "You can't join into an ongoing fight, sorry Kappa",
+                            message_id,
                         );
                     }
+                    return;
                 }
-                if let Some(parts) = message_text.strip_prefix("!setcustomskin") {
-                    if name == "kuviman" {
-                        let mut parts = parts.split_whitespace();
-                        if let Some(name) = parts.next() {
-                            if let Some(custom) = parts.next() {
-                                if self.assets.guy.custom.contains_key(custom) {
-                                    let mut skin = self.find_skin(name, false).await;
-                                    skin.custom = Some(custom.to_owned());
-                                    self.db.set_skin(name, &skin);
-                                    if let Some(guy) =
-                                        self.guys.iter_mut().find(|guy| guy.name == name)
-                                    {
-                                        guy.skin = skin;
-                                    }
+                let Some(command) = self.commands.parse(&message, &self.connection).await else { return };
+                match command.command.as_str() {
+                    "submit" => {
+                        let url = command.text("url").unwrap();
+                        let submitted = async {
+                            anyhow::Ok((
+                                self.db.game_played(name).await?,
+                                self.db.find_game_link(name).await?.is_some(),
+                            ))
+                        }
+                        .await;
+                        let (played, submitted) = match submitted {
+                            Ok(submitted) => submitted,
+                            Err(e) => {
+                                self.storage_error(&command, e);
+                                return;
+                            }
+                        };
+                        if played {
+                            command.reply(&self.connection, "We have already played your game 😕");
+                        } else if submitted {
+                            command.reply(
+                                &self.connection,
+                                "You have already submitted a game tho 😕",
+                            );
+                        } else {
+                            self.db.set_game_link(name, Some(url));
+
+                            let mut text = "Submission successful 👌".to_owned();
+                            if let Some(guy) = self.guys.iter_mut().find(|guy| guy.name == name) {
+                                if guy.should_never_win {
+                                    guy.should_never_win = false;
+                                    text += " Your curse has been reversed";
                                 }
                             }
+                            command.reply(&self.connection, &text);
                         }
                     }
-                }
-                if let Some(face) = message_text.strip_prefix("!face") {
-                    let face = face.trim();
-                    if self.assets.guy.face.contains_key(face) {
-                        let mut skin = self.find_skin(name, false).await;
-                        skin.face = face.to_owned();
-                        self.db.set_skin(name, &skin);
-                        if let Some(guy) = self.guys.iter_mut().find(|guy| guy.name == name) {
-                            guy.skin = skin;
-                        }
-                    } else {
-                        self.connection.reply(
-                            &format!(
-                                "⚙️ Face options: {}",
-                                self.assets
-                                    .guy
-                                    .face
-                                    .keys()
-                                    .map(|s| s.as_str())
-                                    .collect::<Vec<_>>()
-                                    .join(", ")
-                            ),
-                            &message_id,
-                        );
-                    }
-                }
-                if let Some(robe) = message_text.strip_prefix("!robe") {
-                    let robe = robe.trim();
-                    if self.assets.guy.robe.contains_key(robe) {
-                        let mut skin = self.find_skin(name, false).await;
-                        skin.robe = robe.to_owned();
-                        self.db.set_skin(name, &skin);
-                        if let Some(guy) = self.guys.iter_mut().find(|guy| guy.name == name) {
-                            guy.skin = skin;
-                        }
-                    } else {
-                        self.connection.reply(
-                            &format!(
-                                "⚙️ Robe options: {}",
-                                self.assets
-                                    .guy
-                                    .robe
-                                    .keys()
-                                    .map(|s| s.as_str())
-                                    .collect::<Vec<_>>()
-                                    .join(", ")
-                            ),
-                            &message_id,
-                        );
-                    }
-                }
-                if let Some(beard) = message_text.strip_prefix("!beard") {
-                    let beard = beard.trim();
-                    if self.assets.guy.beard.contains_key(beard) {
-                        let mut skin = self.find_skin(name, false).await;
-                        skin.beard = beard.to_owned();
-                        self.db.set_skin(name, &skin);
-                        if let Some(guy) = self.guys.iter_mut().find(|guy| guy.name == name) {
-                            guy.skin = skin;
+                    "hat" | "face" | "robe" | "beard" => {
+                        let part = command.command.as_str();
+                        let options = match part {
+                            "hat" => &self.assets.guy.hat,
+                            "face" => &self.assets.guy.face,
+                            "robe" => &self.assets.guy.robe,
+                            "beard" => &self.assets.guy.beard,
+                            _ => unreachable!(),
+                        };
+                        let value = command.text(part).unwrap_or_default();
+                        if options.contains_key(value) {
+                            let skin = self
+                                .db
+                                .update_skin(
+                                    name,
+                                    || self.new_skin(name),
+                                    |skin| {
+                                        let value = value.to_owned();
+                                        match part {
+                                            "hat" => skin.hat = value,
+                                            "face" => skin.face = value,
+                                            "robe" => skin.robe = value,
+                                            "beard" => skin.beard = value,
+                                            _ => unreachable!(),
+                                        }
+                                    },
+                                )
+                                .await;
+                            let Some(skin) = skin else {
+                                command.reply(
+                                    &self.connection,
+                                    "Something went wrong, try again later 😕",
+                                );
+                                return;
+                            };
+                            if let Some(guy) = self.guys.iter_mut().find(|guy| guy.name == name) {
+                                guy.skin = skin;
+                            }
+                        } else {
+                            let mut options: Vec<&str> =
+                                options.keys().map(|s| s.as_str()).collect();
+                            options.sort();
+                            command.reply(
+                                &self.connection,
+                                &format!(
+                                    "⚙️ {}{} options: {}",
+                                    part[..1].to_uppercase(),
+                                    &part[1..],
+                                    options.join(", "),
+                                ),
+                            );
                         }
-                    } else {
-                        self.connection.reply(
-                            &format!(
-                                "⚙️ Beard options: {}",
-                                self.assets
-                                    .guy
-                                    .beard
-                                    .keys()
-                                    .map(|s| s.as_str())
-                                    .collect::<Vec<_>>()
-                                    .join(", ")
-                            ),
-                            &message_id,
-                        );
                     }
-                }
-                if message_text.trim() == format!("!{}", self.raffle_keyword) {
-                    if self.idle {
-                        self.connection
-                            .reply("You are either too late or too early 😊", &message_id);
-                    } else if !self.process_battle {
-                        if self.guys.iter().any(|guy| guy.name == name) {
-                            self.connection.reply("No cheating allowed 🚫", &message_id);
-                        } else {
-                            self.spawn_guy(name.to_owned(), false).await;
-                            if self.raffle_mode == RaffleMode::Ld
-                                && self.db.find_game_link(name).await.is_none()
-                            {
-                                self.connection.reply("You didn't !submit a game so you are cursed. Submit to reverse it ⏳", &message_id);
+                    "setcustomskin" => {
+                        let name = command.text("name").unwrap();
+                        let custom = command.text("skin").unwrap();
+                        if self.assets.guy.custom.contains_key(custom) {
+                            let skin = self
+                                .db
+                                .update_skin(
+                                    name,
+                                    || self.new_skin(name),
+                                    |skin| skin.custom = Some(custom.to_owned()),
+                                )
+                                .await;
+                            let Some(skin) = skin else {
+                                command.reply(
+                                    &self.connection,
+                                    "Something went wrong, try again later 😕",
+                                );
+                                return;
+                            };
+                            if let Some(guy) = self.guys.iter_mut().find(|guy| guy.name == name) {
+                                guy.skin = skin;
                             }
                         }
-                    } else {
-                        self.connection.reply(
-                            "You can't join into an ongoing fight, sorry Kappa",
-                            &message_id,
-                        );
                     }
-                }
-                if let Some(keyword) = message_text.strip_prefix("!raffle") {
-                    if name == "kuviman" {
-                        let keyword = keyword.trim();
+                    "raffle" => {
+                        let keyword = command.text("keyword").unwrap_or_default();
                         if keyword.is_empty() {
                             self.start_raffle(RaffleMode::Ld);
                         } else if keyword == "start" {
@@ -207,16 +236,14 @@ impl State {
                             self.start_raffle(RaffleMode::Ld);
                         }
                     }
-                }
-                if name == "kuviman" {
-                    if let Some(name) = message_text.strip_prefix("!curse") {
-                        let name = name.trim();
+                    "curse" => {
+                        let name = command.text("name").unwrap();
                         if let Some(guy) = self.guys.iter_mut().find(|guy| guy.name == name) {
                             guy.should_never_win = true;
                         }
                     }
-                    if let Some(name) = message_text.strip_prefix("!bless") {
-                        let name = name.trim();
+                    "bless" => {
+                        let name = command.text("name").unwrap();
                         if let Some(guy) = self.guys.iter_mut().find(|guy| guy.name == name) {
                             guy.health += self.assets.constants.bless_hp;
                             guy.max_health += self.assets.constants.bless_hp;
@@ -239,38 +266,75 @@ impl State {
                             });
                         }
                     }
-                    if let Some(names) = message_text.strip_prefix("!spawn") {
-                        for name in names.split_whitespace() {
-                            self.spawn_guy(name.to_owned(), true).await;
+                    "spawn" => {
+                        for name in command.text("names").unwrap().split_whitespace() {
+                            if let Err(e) = self.spawn_guy(name.to_owned(), true).await {
+                                self.storage_error(&command, e);
+                            }
                         }
                     }
-                }
-                match message_text.trim() {
-                    "!lvl" | "!level" => {
-                        let level = self.db.find_level(&name).await;
+                    "lvl" => {
+                        let level = match self.db.find_level(name).await {
+                            Ok(level) => level,
+                            Err(e) => {
+                                self.storage_error(&command, e);
+                                return;
+                            }
+                        };
                         let hp = self.assets.constants.initial_health
                             + (level.max(1) - 1) * self.assets.constants.extra_health_per_level;
-                        self.connection.reply(
+                        command.reply(
+                            &self.connection,
                             &format!("You are level {} ({} hp) ⭐", level, hp),
-                            &message_id,
                         );
                     }
-                    "!skin" => {
-                        let skin = self.find_skin(name, true).await;
-                        self.connection.reply(&skin.to_string(), &message_id);
-                    }
-                    "!skin random" => {
-                        let skin = Skin::random(&self.assets);
-                        self.db.set_skin(name, &skin);
-                        if let Some(guy) = self.guys.iter_mut().find(|guy| guy.name == name) {
-                            guy.skin = skin;
+                    "skin" => match command.text("random") {
+                        Some("random") => {
+                            let skin = Skin::random(&self.assets);
+                            self.db.set_skin(name, &skin);
+                            if let Some(guy) = self.guys.iter_mut().find(|guy| guy.name == name) {
+                                guy.skin = skin;
+                            }
                         }
-                    }
-                    _ => {}
+                        _ => match self.find_skin(name, true).await {
+                            Ok(skin) => command.reply(&self.connection, &skin.to_string()),
+                            Err(e) => self.storage_error(&command, e),
+                        },
+                    },
+                    _ => unreachable!(),
                 }
             }
-            ServerMessage::RewardRedemption { name, reward } => {
-                if reward == "Raffle Royale Level Up" {
+            ServerMessage::RewardRedemption {
+                channel,
+                id,
+                name,
+                key,
+                ..
+            } => {
+                if key.as_deref() == Some("raffle_royale_level_up") {
+                    if !self.guys.iter().any(|guy| guy.name == name) {
+                        self.connection
+                            .update_redemption(&id, RedemptionStatus::Canceled);
+                        self.connection.say_in(
+                            &channel,
+                            &format!("{name} is not in the battle, points refunded 💸"),
+                        );
+                        return;
+                    }
+                    let Some(level) = self
+                        .db
+                        .add_levels(&name, self.assets.constants.channel_point_levels)
+                        .await
+                    else {
+                        self.connection
+                            .update_redemption(&id, RedemptionStatus::Canceled);
+                        self.connection.say_in(
+                            &channel,
+                            &format!("Could not level up {name}, points refunded 💸"),
+                        );
+                        return;
+                    };
+                    // Could have died while we were waiting for the storage
                     if let Some(guy) = self.guys.iter_mut().find(|guy| guy.name == name) {
                         let extra_hp = self.assets.constants.extra_health_per_level
                             * self.assets.constants.channel_point_levels;
@@ -293,13 +357,14 @@ impl State {
                             color: Rgba::YELLOW,
                         });
                     }
-                    let level = self.db.find_level(&name).await
-                        + self.assets.constants.channel_point_levels;
-                    self.db.set_level(&name, level);
                     let hp = self.assets.constants.initial_health
                         + (level.max(1) - 1) * self.assets.constants.extra_health_per_level;
+                    self.connection.say_in(
+                        &channel,
+                        &format!("{} is now level {} ({} hp) ⭐", name, level, hp),
+                    );
                     self.connection
-                        .say(&format!("{} is now level {} ({} hp) ⭐", name, level, hp));
+                        .update_redemption(&id, RedemptionStatus::Fulfilled);
                 }
             }
             _ => {}
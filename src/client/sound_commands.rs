@@ -1,6 +1,8 @@
 use super::*;
 
 pub struct State {
+    connection: Connection,
+    commands: Commands,
     sounds: HashMap<String, geng::Sound>,
 }
 
@@ -20,7 +22,7 @@ impl Feature for State {
             let path = path.clone();
             async move {
                 (
-                    format!("!{name}"),
+                    name.to_lowercase(),
                     <geng::Sound as geng::asset::Load>::load(
                         geng.asset_manager(),
                         &path.join(format!("{name}.wav")),
@@ -33,18 +35,38 @@ impl Feature for State {
         }))
         .await
         .into_iter()
-        .collect();
-        Self { sounds }
+        .collect::<HashMap<String, geng::Sound>>();
+        let mut commands = Commands::new();
+        for name in sounds.keys() {
+            commands.add(
+                Command::new(name)
+                    .description("Play a sound")
+                    .cooldown(Cooldown {
+                        global: 5.0,
+                        user: 30.0,
+                        reply: false,
+                    }),
+            );
+        }
+        let commands = commands.with_cooldowns(&load_cooldowns(&geng, &path).await);
+        Self {
+            connection,
+            commands,
+            sounds,
+        }
     }
 
     async fn update(&mut self, _delta_time: f32) {}
 
     fn draw(&mut self, _framebuffer: &mut ugli::Framebuffer) {}
 
+    fn commands(&self) -> Vec<Command> {
+        self.commands.iter().cloned().collect()
+    }
+
     async fn handle(&mut self, message: &ServerMessage) {
         let this = self;
-        let ServerMessage::ChatMessage { message, .. } = message else { return };
-        let Some(sound) = this.sounds.get(message.trim()) else { return };
-        sound.play();
+        let Some(command) = this.commands.parse(message, &this.connection).await else { return };
+        this.sounds[&command.command].play();
     }
 }
@@ -1,7 +1,9 @@
 use super::*;
 
 pub struct State {
+    /// Command name to reply
     config: HashMap<String, String>,
+    commands: Commands,
     connection: Connection,
 }
 
@@ -15,8 +17,22 @@ impl Feature for State {
             geng::asset::Load::load(geng.asset_manager(), &path.join("config.json"), &default())
                 .await
                 .unwrap();
+        let config: HashMap<String, String> = serde_json::from_str(&json).unwrap();
+        let config: HashMap<String, String> = config
+            .into_iter()
+            .map(|(command, reply)| {
+                let name = command.strip_prefix('!').unwrap_or(&command).to_lowercase();
+                (name, reply)
+            })
+            .collect();
+        let mut commands = Commands::new();
+        for name in config.keys() {
+            commands.add(Command::new(name));
+        }
+        let commands = commands.with_cooldowns(&load_cooldowns(&geng, &path).await);
         Self {
-            config: serde_json::from_str(&json).unwrap(),
+            config,
+            commands,
             connection,
         }
     }
@@ -25,10 +41,14 @@ impl Feature for State {
 
     fn draw(&mut self, _framebuffer: &mut ugli::Framebuffer) {}
 
+    fn commands(&self) -> Vec<Command> {
+        self.commands.iter().cloned().collect()
+    }
+
     async fn handle(&mut self, message: &ServerMessage) {
         let this = self;
-        let ServerMessage::ChatMessage { message, .. } = message else { return };
-        let Some(reply) = this.config.get(message.trim()) else { return };
-        this.connection.say(reply);
+        let Some(command) = this.commands.parse(message, &this.connection).await else { return };
+        this.connection
+            .say_in(&command.message_id.channel, &this.config[&command.command]);
     }
 }
@@ -3,3 +3,24 @@ use super::*;
 pub fn pixelate(texture: &mut ugli::Texture) {
     texture.set_filter(ugli::Filter::Nearest);
 }
+
+/// Make text (like a display name, which can be localized) usable as a key value storage segment.
+/// Plain ascii names stay the same, everything else is percent encoded.
+pub fn key_segment(text: &str) -> String {
+    let mut result = String::new();
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'-') {
+            result.push(byte as char);
+        } else {
+            result.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    result
+}
+
+#[test]
+fn test_key_segment() {
+    assert_eq!(key_segment("Kuviman_123"), "Kuviman_123");
+    assert_eq!(key_segment("ニャン"), "%E3%83%8B%E3%83%A3%E3%83%B3");
+    assert_eq!(key_segment("../x"), "%2E%2E%2Fx");
+}
@@ -4,40 +4,358 @@ mod client;
 #[cfg(not(target_arch = "wasm32"))]
 mod server;
 
+/// Must match between server and client, bump on incompatible changes to the messages
+pub const PROTOCOL_VERSION: u32 = 10;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum ServerMessage {
+    /// Reply to [ClientMessage::Hello].
+    /// Until `ready` the client should retry the handshake and not send anything else.
+    Welcome {
+        protocol_version: u32,
+        client_id: ClientId,
+        ready: bool,
+        capabilities: Vec<String>,
+    },
     ChatMessage {
+        /// Login of the channel it happened in
+        channel: String,
         id: MessageId,
         name: String,
         message: String,
+        user: ChatUser,
+        emotes: Vec<Emote>,
+        /// Amount of bits cheered with the message
+        bits: Option<u64>,
+        /// First message of the user in the channel
+        first_message: bool,
+        /// Message this one is replying to
+        reply_parent: Option<ReplyParent>,
+        /// When the message was sent (unix time in seconds)
+        timestamp: f64,
     },
     RewardRedemption {
+        /// Login of the channel it happened in
+        channel: String,
+        /// Redemption id, for [ClientMessage::UpdateRedemption]
+        id: String,
         name: String,
+        /// Title of the reward
         reward: String,
+        /// Key of the reward in the server config, if the server manages it
+        key: Option<String>,
+        reward_id: String,
+        cost: u64,
+        /// Text the user entered, for rewards that ask for it
+        input: Option<String>,
+    },
+    Follow {
+        /// Login of the channel it happened in
+        channel: String,
+        name: String,
+    },
+    /// New subscription or resubscription shared in chat
+    Subscription {
+        /// Login of the channel it happened in
+        channel: String,
+        name: String,
+        tier: SubscriptionTier,
+        /// Total months subscribed, including this one
+        months: u64,
+        /// What they said when sharing the resub
+        message: Option<String>,
+    },
+    /// Sent for each recipient, even when gifting many subs at once
+    GiftSubscription {
+        /// Login of the channel it happened in
+        channel: String,
+        /// `None` if gifted anonymously
+        gifter: Option<String>,
+        recipient: String,
+        tier: SubscriptionTier,
+    },
+    Cheer {
+        /// Login of the channel it happened in
+        channel: String,
+        name: String,
+        bits: u64,
+        message: String,
+    },
+    Raid {
+        /// Login of the channel it happened in
+        channel: String,
+        name: String,
+        viewers: u64,
+    },
+    /// Whether the server receives twitch events (like channel point redemptions).
+    /// Sent when it changes and after the handshake.
+    EventStreamHealth {
+        connected: bool,
+        error: Option<String>,
     },
     KeyValue {
         request_id: String,
         value: Option<String>,
     },
+    Keys {
+        request_id: String,
+        keys: Vec<String>,
+    },
+    KeyDeleted {
+        request_id: String,
+        existed: bool,
+    },
+    Incremented {
+        request_id: String,
+        value: i64,
+    },
+    /// Reply to [ClientMessage::CompareAndSwap], `current` is the value after the operation
+    Swapped {
+        request_id: String,
+        success: bool,
+        current: Option<String>,
+    },
+    /// Pushed to clients that sent [ClientMessage::Watch] for the key
+    KeyChanged {
+        namespace: String,
+        key: String,
+        value: Option<String>,
+    },
+    /// Client should reply with [ClientMessage::Pong], otherwise it is considered disconnected
+    Ping,
+    Clients {
+        request_id: String,
+        clients: Vec<ClientInfo>,
+    },
+    /// Moderation request succeeded
+    Moderated {
+        request_id: String,
+    },
+    /// Request could not be processed, `request_id` is set if the request had one
+    Error {
+        request_id: Option<String>,
+        message: String,
+    },
+}
+
+impl ServerMessage {
+    /// Id of the request this message is a reply to
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            Self::KeyValue { request_id, .. }
+            | Self::Keys { request_id, .. }
+            | Self::KeyDeleted { request_id, .. }
+            | Self::Incremented { request_id, .. }
+            | Self::Swapped { request_id, .. }
+            | Self::Clients { request_id, .. }
+            | Self::Moderated { request_id } => Some(request_id),
+            Self::Error { request_id, .. } => request_id.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Login of the channel this message is about, `None` for messages that are not about a channel
+    pub fn channel(&self) -> Option<&str> {
+        match self {
+            Self::ChatMessage { channel, .. }
+            | Self::RewardRedemption { channel, .. }
+            | Self::Follow { channel, .. }
+            | Self::Subscription { channel, .. }
+            | Self::GiftSubscription { channel, .. }
+            | Self::Cheer { channel, .. }
+            | Self::Raid { channel, .. } => Some(channel),
+            _ => None,
+        }
+    }
 }
 
+/// Chat message ids are only meaningful in the channel the message was sent to
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct MessageId(String);
+pub struct MessageId {
+    channel: String,
+    id: String,
+}
+
+/// Id of an overlay client connected to the server
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ClientId(u64);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClientInfo {
+    pub id: ClientId,
+    /// Seconds since the client connected
+    pub connected_for: f64,
+}
+
+/// What to do with a channel point redemption waiting in the reward queue
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RedemptionStatus {
+    Fulfilled,
+    /// Points are refunded
+    Canceled,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubscriptionTier {
+    Prime,
+    Tier1,
+    Tier2,
+    Tier3,
+}
+
+/// Sender of a chat message
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChatUser {
+    pub id: String,
+    pub login: String,
+    pub display_name: String,
+    /// Name color picked in chat settings
+    pub color: Option<Rgba<f32>>,
+    pub badges: Vec<Badge>,
+    /// Decided by the server from badges and configured permissions
+    pub role: Role,
+}
+
+/// What the user is allowed to do, every role can do everything the previous ones can
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Everyone,
+    Subscriber,
+    Vip,
+    Moderator,
+    Broadcaster,
+}
+
+impl ChatUser {
+    /// User we only know the name of, like the ones impersonated for testing
+    pub fn from_name(name: &str) -> Self {
+        Self {
+            id: String::new(),
+            login: name.to_lowercase(),
+            display_name: name.to_owned(),
+            color: None,
+            badges: vec![],
+            role: Role::Everyone,
+        }
+    }
+
+    pub fn has_badge(&self, name: &str) -> bool {
+        self.badges.iter().any(|badge| badge.name == name)
+    }
+
+    pub fn has_role(&self, role: Role) -> bool {
+        self.role >= role
+    }
+}
+
+/// Like `broadcaster/1` or `subscriber/12`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Badge {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Emote {
+    pub id: String,
+    pub code: String,
+    /// Range of chars (not bytes) in the message text
+    pub char_range: std::ops::Range<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReplyParent {
+    pub id: MessageId,
+    pub user_login: String,
+    pub display_name: String,
+    pub message: String,
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum ClientMessage {
+    /// First message after connecting
+    Hello {
+        protocol_version: u32,
+        /// Logins of the channels to get messages from, all of them if empty
+        channels: Vec<String>,
+    },
+    Pong,
+    /// Ask which overlay clients are currently connected
+    ListClients {
+        request_id: String,
+    },
     Say {
         text: String,
         reply_to: Option<MessageId>,
+        /// Defaults to the channel of `reply_to`, or the first channel the client gets messages from
+        channel: Option<String>,
+    },
+    /// Complete or refund a channel point redemption
+    UpdateRedemption {
+        id: String,
+        status: RedemptionStatus,
+    },
+    /// Answered with [ServerMessage::Moderated] or [ServerMessage::Error], like the other moderation requests
+    DeleteMessage {
+        request_id: String,
+        id: MessageId,
+    },
+    /// Time out the user for `duration` seconds
+    Timeout {
+        request_id: String,
+        channel: String,
+        login: String,
+        duration: u64,
+        reason: String,
+    },
+    Ban {
+        request_id: String,
+        channel: String,
+        login: String,
+        reason: String,
     },
     GetKeyValue {
         request_id: String,
+        namespace: String,
         key: String,
     },
     SetKeyValue {
+        namespace: String,
         key: String,
         value: String,
     },
+    ListKeys {
+        request_id: String,
+        namespace: String,
+        prefix: String,
+    },
+    DeleteKey {
+        request_id: String,
+        namespace: String,
+        key: String,
+    },
+    /// Get notified with [ServerMessage::KeyChanged] when keys starting with the prefix change
+    Watch {
+        namespace: String,
+        prefix: String,
+    },
+    /// Atomically add `delta` to an integer value, missing value counts as 0
+    Increment {
+        request_id: String,
+        namespace: String,
+        key: String,
+        delta: i64,
+    },
+    /// Atomically set the value if it is currently equal to `expected`.
+    /// `None` means the key is absent.
+    CompareAndSwap {
+        request_id: String,
+        namespace: String,
+        key: String,
+        expected: Option<String>,
+        value: Option<String>,
+    },
 }
 
 #[derive(clap::Parser)]
@@ -48,6 +366,15 @@ struct Opt {
     pub connect: Option<String>,
     #[clap(long)]
     pub serve: Option<std::path::PathBuf>,
+    /// Only get messages from this channel, can be repeated
+    #[clap(long = "channel")]
+    pub channels: Vec<String>,
+    /// Log in as this twitch account in the browser, save its tokens and exit
+    #[clap(long)]
+    pub authorize: Option<String>,
+    /// Authorize by entering a code on another device, for machines without a browser
+    #[clap(long)]
+    pub device_code: bool,
     #[clap(flatten)]
     pub geng: geng::CliArgs,
 }
@@ -63,6 +390,12 @@ fn main() {
 
     let mut opt: Opt = cli::parse();
 
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(login) = &opt.authorize {
+        server::authorize(login, opt.device_code);
+        return;
+    }
+
     if opt.connect.is_none() && opt.server.is_none() {
         if cfg!(target_arch = "wasm32") {
             opt.connect = Some("ws://127.0.0.1:1155".to_owned());
@@ -83,8 +416,6 @@ fn main() {
                 server::run(&addr, opt.serve.as_deref());
             });
         }
-        #[cfg(not(target_arch = "wasm32"))]
-        std::thread::sleep_ms(5000);
-        client::run(&opt.geng, opt.connect.as_deref().unwrap());
+        client::run(&opt.geng, opt.connect.as_deref().unwrap(), &opt.channels);
     }
 }
@@ -1,10 +1,92 @@
 use super::*;
 
+/// Twitch counts sent messages over this period
+const RATE_LIMIT_PERIOD: std::time::Duration = std::time::Duration::from_secs(30);
+/// How many messages we can send per period
+const RATE_LIMIT: usize = 20;
+/// Same for moderators and the broadcaster
+const MODERATOR_RATE_LIMIT: usize = 100;
+/// Longer messages are rejected by twitch (counting chars, not bytes)
+const MAX_MESSAGE_LENGTH: usize = 500;
+/// Features update redemptions right away, so the ones nobody took care of are forgotten after this
+const REDEMPTION_MEMORY: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+struct OutgoingMessage {
+    channel: String,
+    text: String,
+    reply_to: Option<String>,
+    /// Overlay client that asked for it, to report failures back
+    from: Option<ClientId>,
+}
+
+struct SentMessage {
+    at: std::time::Instant,
+    channel: String,
+    text: String,
+    reply_to: Option<String>,
+}
+
 pub struct Bot {
     config: Config,
     ttv_client: ttv::Client,
     sender: Sender,
-    receiver: std::sync::mpsc::Receiver<ClientMessage>,
+    receiver: std::sync::mpsc::Receiver<(ClientId, ClientMessage)>,
+    outgoing: std::collections::VecDeque<OutgoingMessage>,
+    /// Messages sent during the last [RATE_LIMIT_PERIOD]
+    recently_sent: std::collections::VecDeque<SentMessage>,
+    /// Channels where we are a moderator and can send more, we learn it from USERSTATE after joining
+    moderator_channels: std::collections::HashSet<String>,
+    /// When redemptions of our rewards came, with their channels and reward ids,
+    /// since updating a redemption needs them
+    redemption_rewards: HashMap<String, (std::time::Instant, String, String)>,
+    /// Keys of the rewards we manage by their ids
+    reward_keys: HashMap<String, String>,
+    /// Latest health of every EventSub session (bot and broadcasters) by their logins
+    event_streams: HashMap<String, (bool, Option<String>)>,
+}
+
+/// Split the text into parts of at most `max_len` chars, preferably at whitespace
+fn split_message(text: &str, max_len: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut rest = text.trim();
+    while let Some((limit, _)) = rest.char_indices().nth(max_len) {
+        let split = rest[..limit]
+            .rfind(char::is_whitespace)
+            .filter(|&index| index > 0)
+            .unwrap_or(limit);
+        parts.push(rest[..split].trim_end().to_owned());
+        rest = rest[split..].trim_start();
+    }
+    if !rest.is_empty() {
+        parts.push(rest.to_owned());
+    }
+    parts
+}
+
+#[test]
+fn test_split_message() {
+    assert_eq!(split_message("hello world", 500), ["hello world"]);
+    assert_eq!(split_message("hello world", 8), ["hello", "world"]);
+    assert_eq!(split_message("abcdefgh", 3), ["abc", "def", "gh"]);
+    assert_eq!(split_message("🦀🦀🦀", 2), ["🦀🦀", "🦀"]);
+    assert!(split_message("   ", 10).is_empty());
+}
+
+/// Sync the rewards of the channel, remembering their ids in the storage
+fn sync_rewards(
+    ttv_client: &ttv::Client,
+    storage: &mut dyn Storage,
+    channel: &str,
+    rewards: &HashMap<String, rewards::Reward>,
+) -> eyre::Result<HashMap<String, String>> {
+    let key = storage::full_key("bot", &format!("rewards/{channel}.json"))?;
+    let saved: HashMap<String, String> = match storage.get(&key)? {
+        Some(json) => serde_json::from_str(&json)?,
+        None => default(),
+    };
+    let ids = ttv_client.sync_rewards(channel, rewards, &saved)?;
+    storage.set(&key, &serde_json::to_string(&ids)?)?;
+    Ok(ids)
 }
 
 impl Bot {
@@ -12,43 +94,269 @@ impl Bot {
         config: Config,
         ttv_client: ttv::Client,
         sender: Sender,
-        receiver: std::sync::mpsc::Receiver<ClientMessage>,
+        receiver: std::sync::mpsc::Receiver<(ClientId, ClientMessage)>,
+        storage: Arc<Mutex<Box<dyn Storage>>>,
     ) -> Self {
+        let mut reward_keys = HashMap::new();
+        for channel in &config.channels {
+            match sync_rewards(
+                &ttv_client,
+                &mut **storage.lock().unwrap(),
+                channel,
+                &config.rewards,
+            ) {
+                Ok(ids) => reward_keys.extend(ids.into_iter().map(|(key, id)| (id, key))),
+                Err(e) => log::error!("Failed to set up channel point rewards in {channel}: {e:#}"),
+            }
+        }
         Self {
             config,
             ttv_client,
             sender,
             receiver,
+            outgoing: default(),
+            recently_sent: default(),
+            moderator_channels: default(),
+            redemption_rewards: default(),
+            reward_keys,
+            event_streams: default(),
+        }
+    }
+
+    /// Queue a chat message, it is sent as soon as rate limits allow
+    pub fn say(
+        &mut self,
+        channel: &str,
+        text: &str,
+        reply_to: Option<String>,
+        from: Option<ClientId>,
+    ) {
+        for text in split_message(text, MAX_MESSAGE_LENGTH) {
+            // Twitch drops a message identical to one sent to the channel less than 30 seconds ago,
+            // unless we are a moderator there. Replies to different messages are not identical.
+            let same = |sent_channel: &str, sent: &str, sent_reply_to: &Option<String>| {
+                sent_channel == channel && sent == text && *sent_reply_to == reply_to
+            };
+            let duplicate = !self.moderator_channels.contains(channel)
+                && (self
+                    .outgoing
+                    .iter()
+                    .any(|message| same(&message.channel, &message.text, &message.reply_to))
+                    || self
+                        .recently_sent
+                        .iter()
+                        .any(|sent| same(&sent.channel, &sent.text, &sent.reply_to)));
+            if duplicate {
+                log::warn!("Dropping duplicate chat message {text:?}");
+                self.report_failure(from, format!("Dropped duplicate chat message {text:?}"));
+                continue;
+            }
+            self.outgoing.push_back(OutgoingMessage {
+                channel: channel.to_owned(),
+                text,
+                reply_to: reply_to.clone(),
+                from,
+            });
+        }
+    }
+
+    fn update_redemption(&mut self, id: &str, status: RedemptionStatus, from: ClientId) {
+        // Can only be done once anyway
+        let Some((_, channel, reward_id)) = self.redemption_rewards.remove(id) else {
+            self.report_failure(Some(from), format!("Unknown redemption {id:?}"));
+            return;
+        };
+        if let Err(e) = self
+            .ttv_client
+            .update_redemption(&channel, &reward_id, id, status)
+        {
+            log::error!("Failed to mark redemption {id:?} as {status:?}: {e:#}");
+            self.report_failure(Some(from), e.to_string());
+        }
+    }
+
+    fn report_failure(&self, client: Option<ClientId>, message: String) {
+        if let Some(client) = client {
+            self.sender.send_to(
+                client,
+                ServerMessage::Error {
+                    request_id: None,
+                    message,
+                },
+            );
+        }
+    }
+
+    fn report_moderation(&self, client: ClientId, request_id: String, result: eyre::Result<()>) {
+        let reply = match result {
+            Ok(()) => ServerMessage::Moderated { request_id },
+            Err(e) => {
+                log::error!("Moderation request failed: {e:#}");
+                ServerMessage::Error {
+                    request_id: Some(request_id),
+                    message: format!("{e:#}"),
+                }
+            }
+        };
+        self.sender.send_to(client, reply);
+    }
+
+    /// Send as much of the queue as rate limits allow
+    fn flush_outgoing(&mut self) {
+        while let Some(sent) = self.recently_sent.front() {
+            if sent.at.elapsed() < RATE_LIMIT_PERIOD {
+                break;
+            }
+            self.recently_sent.pop_front();
+        }
+        while let Some(message) = self.outgoing.front() {
+            let limit = if self.moderator_channels.contains(&message.channel) {
+                MODERATOR_RATE_LIMIT
+            } else {
+                RATE_LIMIT
+            };
+            if self.recently_sent.len() >= limit {
+                break;
+            }
+            let message = self.outgoing.pop_front().unwrap();
+            if let Err(e) =
+                self.ttv_client
+                    .say(&message.channel, &message.text, message.reply_to.clone())
+            {
+                log::error!("{e}");
+                self.report_failure(message.from, e.to_string());
+                continue;
+            }
+            self.recently_sent.push_back(SentMessage {
+                at: std::time::Instant::now(),
+                channel: message.channel,
+                text: message.text,
+                reply_to: message.reply_to,
+            });
         }
     }
     pub fn handle_ttv(&mut self, message: ttv::Message) {
         match message {
             ttv::Message::Irc(ttv::IrcMessage::Privmsg(message)) => {
+                let channel = message.channel_login.as_str();
                 let mut name = message.sender.name.as_str();
                 let mut message_text = message.message_text.as_str();
-                if name == self.config.channel_login {
+                let mut user = ttv::chat_user(&message);
+                user.role = self.config.permissions.role(&user);
+                let mut emotes = ttv::emotes(&message);
+                if user.has_role(Role::Broadcaster) {
                     if let Some(text) = message_text.strip_prefix("!as") {
                         if let Some((as_name, text)) = text.trim().split_once(' ') {
                             name = as_name.trim();
                             message_text = text.trim();
+                            user = ChatUser::from_name(name);
+                            user.role = self.config.permissions.role(&user);
+                            // Ranges point into the original text
+                            emotes.clear();
                         }
                     }
                 }
                 log::info!("{}", message_text);
                 match message_text.trim() {
-                    "!gnbadcop" => self.ttv_client.say("Good Night badcop_ rincsDance", None),
-                    "🦀" => self.ttv_client.say("🦀✅🦀✅🦀✅🦀✅🦀✅🦀✅🦀", None),
+                    "!gnbadcop" => self.say(channel, "Good Night badcop_ rincsDance", None, None),
+                    "🦀" => self.say(channel, "🦀✅🦀✅🦀✅🦀✅🦀✅🦀✅🦀", None, None),
                     _ => {}
                 }
+                if let Some(bits) = message.bits {
+                    self.sender.broadcast(ServerMessage::Cheer {
+                        channel: channel.to_owned(),
+                        name: name.to_owned(),
+                        bits,
+                        message: message_text.to_owned(),
+                    });
+                }
                 self.sender.broadcast(ServerMessage::ChatMessage {
-                    id: MessageId(message.message_id.clone()),
+                    channel: channel.to_owned(),
+                    id: MessageId {
+                        channel: channel.to_owned(),
+                        id: message.message_id.clone(),
+                    },
                     name: name.to_owned(),
                     message: message_text.to_owned(),
+                    user,
+                    emotes,
+                    bits: message.bits,
+                    first_message: ttv::tag(&message.source, "first-msg") == Some("1"),
+                    reply_parent: ttv::reply_parent(&message),
+                    timestamp: message.server_timestamp.timestamp_millis() as f64 / 1000.0,
                 });
             }
-            ttv::Message::RewardRedemption { name, reward } => {
+            ttv::Message::Irc(ttv::IrcMessage::UserNotice(notice)) => {
+                if let Some(message) = ttv::user_notice(&notice) {
+                    self.sender.broadcast(message);
+                }
+            }
+            ttv::Message::Irc(ttv::IrcMessage::UserState(state)) => {
+                let is_moderator = state
+                    .badges
+                    .iter()
+                    .any(|badge| badge.name == "moderator" || badge.name == "broadcaster");
+                if is_moderator {
+                    self.moderator_channels.insert(state.channel_login);
+                } else {
+                    self.moderator_channels.remove(&state.channel_login);
+                }
+            }
+            ttv::Message::RewardRedemption {
+                channel,
+                id,
+                name,
+                reward,
+                reward_id,
+                cost,
+                input,
+            } => {
+                let key = self.reward_keys.get(&reward_id).cloned();
+                self.redemption_rewards
+                    .retain(|_, (at, ..)| at.elapsed() < REDEMPTION_MEMORY);
+                // Features only react to the rewards they know the keys of
+                if key.is_some() {
+                    self.redemption_rewards.insert(
+                        id.clone(),
+                        (
+                            std::time::Instant::now(),
+                            channel.clone(),
+                            reward_id.clone(),
+                        ),
+                    );
+                }
+                self.sender.broadcast(ServerMessage::RewardRedemption {
+                    channel,
+                    id,
+                    name,
+                    reward,
+                    key,
+                    reward_id,
+                    cost,
+                    input,
+                });
+            }
+            ttv::Message::Follow { channel, name } => {
                 self.sender
-                    .broadcast(ServerMessage::RewardRedemption { name, reward });
+                    .broadcast(ServerMessage::Follow { channel, name });
+            }
+            ttv::Message::EventStreamHealth {
+                session,
+                connected,
+                error,
+            } => {
+                self.event_streams.insert(session, (connected, error));
+                let errors: Vec<String> = self
+                    .event_streams
+                    .iter()
+                    .filter_map(|(session, (_, error))| {
+                        Some(format!("{session}: {}", error.as_ref()?))
+                    })
+                    .collect();
+                self.sender.set_event_stream_health(
+                    self.event_streams.values().all(|(connected, _)| *connected),
+                    (!errors.is_empty()).then(|| errors.join("; ")),
+                );
             }
             _ => {}
         }
@@ -58,11 +366,48 @@ impl Bot {
             while let Some(msg) = self.ttv_client.next_message() {
                 self.handle_ttv(msg);
             }
-            for msg in self.receiver.try_iter() {
-                if let ClientMessage::Say { text, reply_to } = msg {
-                    self.ttv_client.say(&text, reply_to.map(|id| id.0));
+            while let Ok((from, msg)) = self.receiver.try_recv() {
+                match msg {
+                    ClientMessage::Say {
+                        text,
+                        reply_to,
+                        channel,
+                    } => {
+                        let channel = channel.unwrap_or_else(|| self.config.channels[0].clone());
+                        self.say(&channel, &text, reply_to.map(|id| id.id), Some(from));
+                    }
+                    ClientMessage::UpdateRedemption { id, status } => {
+                        self.update_redemption(&id, status, from);
+                    }
+                    ClientMessage::DeleteMessage { request_id, id } => {
+                        let result = self.ttv_client.delete_message(&id.channel, &id.id);
+                        self.report_moderation(from, request_id, result);
+                    }
+                    ClientMessage::Timeout {
+                        request_id,
+                        channel,
+                        login,
+                        duration,
+                        reason,
+                    } => {
+                        let result = self
+                            .ttv_client
+                            .ban(&channel, &login, Some(duration), &reason);
+                        self.report_moderation(from, request_id, result);
+                    }
+                    ClientMessage::Ban {
+                        request_id,
+                        channel,
+                        login,
+                        reason,
+                    } => {
+                        let result = self.ttv_client.ban(&channel, &login, None, &reason);
+                        self.report_moderation(from, request_id, result);
+                    }
+                    _ => {}
                 }
             }
+            self.flush_outgoing();
             std::thread::sleep(std::time::Duration::from_millis(100));
         }
     }
@@ -1,89 +1,483 @@
 use super::*;
 
 mod bot;
+mod permissions;
+mod rewards;
 mod secret;
+mod storage;
 mod ttv;
 mod util;
 
-use secret::Secrets;
+use secret::{Account, Secrets};
+use storage::Storage;
 use util::*;
 
 #[derive(Deserialize)]
 pub struct Config {
-    pub channel_login: String,
+    /// Logins of the channels to join, the first one is where messages go by default
+    pub channels: Vec<String>,
     pub bot_login: String,
+    #[serde(default)]
+    pub storage: storage::Config,
+    #[serde(default)]
+    pub permissions: permissions::Config,
+    #[serde(default)]
+    pub endpoints: ttv::Endpoints,
+    /// Channel point rewards by key
+    #[serde(default)]
+    pub rewards: HashMap<String, rewards::Reward>,
+}
+
+/// Optional things the server supports, reported to clients in [ServerMessage::Welcome]
+const CAPABILITIES: &[&str] = &[
+    "key_value",
+    "watch",
+    "presence",
+    "event_stream_health",
+    "moderation",
+];
+
+/// How often clients are pinged
+const PING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+/// Clients that did not send anything (including pongs) for this long are considered disconnected
+const CLIENT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+struct RegisteredClient {
+    sender: Arc<Mutex<Box<dyn geng::net::Sender<ServerMessage>>>>,
+    /// Key prefixes (including the namespace) the client is watching
+    watches: Arc<Mutex<Vec<String>>>,
+    connected_at: std::time::Instant,
+    last_seen: std::time::Instant,
+    /// Whether the client completed the handshake and should get broadcasts
+    welcomed: bool,
+    /// Only messages about these channels are broadcast to the client, all if empty
+    channels: Vec<String>,
 }
 
 #[derive(Clone)]
 pub struct Sender {
-    senders: Arc<Mutex<Vec<Arc<Mutex<Box<dyn geng::net::Sender<ServerMessage>>>>>>>,
+    clients: Arc<Mutex<HashMap<ClientId, RegisteredClient>>>,
+    next_id: Arc<std::sync::atomic::AtomicU64>,
+    /// Set once we are logged into twitch
+    ready: Arc<std::sync::atomic::AtomicBool>,
+    /// Last [ServerMessage::EventStreamHealth], for clients connecting later
+    event_stream_health: Arc<Mutex<Option<ServerMessage>>>,
 }
 
 impl Sender {
     pub fn new() -> Self {
         Self {
-            senders: Arc::new(Mutex::new(vec![])),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(std::sync::atomic::AtomicU64::new(0)),
+            ready: Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            event_stream_health: Arc::new(Mutex::new(None)),
         }
     }
     pub fn broadcast(&self, message: ServerMessage) {
-        for sender in &mut *self.senders.lock().unwrap() {
-            sender.lock().unwrap().send(message.clone());
+        for client in self.clients.lock().unwrap().values() {
+            let subscribed = match message.channel() {
+                Some(channel) => {
+                    client.channels.is_empty() || client.channels.iter().any(|c| c == channel)
+                }
+                None => true,
+            };
+            if client.welcomed && subscribed {
+                client.sender.lock().unwrap().send(message.clone());
+            }
+        }
+    }
+
+    pub fn set_event_stream_health(&self, connected: bool, error: Option<String>) {
+        let message = ServerMessage::EventStreamHealth { connected, error };
+        *self.event_stream_health.lock().unwrap() = Some(message.clone());
+        self.broadcast(message);
+    }
+
+    pub fn set_ready(&self) {
+        self.ready.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// Notify the clients watching the key (including the namespace) about its new value
+    pub fn key_changed(&self, key: &str, value: Option<&str>) {
+        let (namespace, namespace_key) = key.split_once('/').unwrap();
+        for client in self.clients.lock().unwrap().values() {
+            let watching = client
+                .watches
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|prefix| key.starts_with(prefix.as_str()));
+            if watching {
+                client
+                    .sender
+                    .lock()
+                    .unwrap()
+                    .send(ServerMessage::KeyChanged {
+                        namespace: namespace.to_owned(),
+                        key: namespace_key.to_owned(),
+                        value: value.map(|value| value.to_owned()),
+                    });
+            }
+        }
+    }
+
+    pub fn register(
+        &self,
+        sender: Arc<Mutex<Box<dyn geng::net::Sender<ServerMessage>>>>,
+        watches: Arc<Mutex<Vec<String>>>,
+    ) -> ClientId {
+        let id = ClientId(
+            self.next_id
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+        );
+        let now = std::time::Instant::now();
+        self.clients.lock().unwrap().insert(
+            id,
+            RegisteredClient {
+                sender,
+                watches,
+                connected_at: now,
+                last_seen: now,
+                welcomed: false,
+                channels: vec![],
+            },
+        );
+        log::info!("Client {id:?} connected");
+        id
+    }
+
+    pub fn unregister(&self, id: ClientId) {
+        if self.clients.lock().unwrap().remove(&id).is_some() {
+            log::info!("Client {id:?} disconnected");
+        }
+    }
+
+    pub fn welcome(&self, id: ClientId, channels: Vec<String>) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&id) {
+            client.welcomed = true;
+            client.channels = channels;
+        }
+    }
+
+    /// Things that a client that just completed the handshake should know about
+    pub fn current_state(&self) -> Vec<ServerMessage> {
+        self.event_stream_health
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Mark that we received something from the client so it is still alive
+    pub fn seen(&self, id: ClientId) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&id) {
+            client.last_seen = std::time::Instant::now();
+        }
+    }
+
+    /// Send to a single client, does nothing if it already disconnected
+    pub fn send_to(&self, id: ClientId, message: ServerMessage) {
+        if let Some(client) = self.clients.lock().unwrap().get(&id) {
+            client.sender.lock().unwrap().send(message);
         }
     }
 
-    pub fn register(&self, sender: Arc<Mutex<Box<dyn geng::net::Sender<ServerMessage>>>>) {
-        self.senders.lock().unwrap().push(sender);
+    pub fn connected_clients(&self) -> Vec<ClientInfo> {
+        let mut clients: Vec<ClientInfo> = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, client)| ClientInfo {
+                id,
+                connected_for: client.connected_at.elapsed().as_secs_f64(),
+            })
+            .collect();
+        clients.sort_by_key(|client| client.id);
+        clients
+    }
+
+    /// Drop clients that timed out (half-open sockets) and ping the rest
+    fn heartbeat(&self) {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|id, client| {
+            let alive = client.last_seen.elapsed() < CLIENT_TIMEOUT;
+            if !alive {
+                log::warn!("Client {id:?} timed out");
+            }
+            alive
+        });
+        for client in clients.values() {
+            client.sender.lock().unwrap().send(ServerMessage::Ping);
+        }
     }
 }
 
-pub fn run(addr: &str, serve_path: Option<&std::path::Path>) {
-    let config: Config =
+fn read_config() -> Config {
+    let mut config: Config =
         serde_json::from_reader(std::fs::File::open("config.json").unwrap()).unwrap();
-    let mut ttv = ttv::Client::new(&config.channel_login, &config.bot_login);
+    assert!(
+        !config.channels.is_empty(),
+        "No channels to join, add at least one to \"channels\" in config.json",
+    );
+    // Twitch logins are lowercase, and we compare them with the ones twitch sends
+    config.bot_login = config.bot_login.to_lowercase();
+    for channel in &mut config.channels {
+        *channel = channel.to_lowercase();
+    }
+    config
+}
+
+/// Log in as a twitch account and save its tokens,
+/// with the scopes needed for what the config uses the account for
+pub fn authorize(login: &str, device_code: bool) {
+    let config = read_config();
+    let login = login.to_lowercase();
+    let accounts = Account::of(&login, &config.bot_login, &config.channels);
+    if accounts.is_empty() {
+        panic!("{login:?} is neither the bot nor one of the channels in config.json");
+    }
+    Secrets::init()
+        .unwrap()
+        .authorize(&login, &accounts, device_code)
+        .unwrap();
+    log::info!("Authorized {login:?} as {accounts:?}");
+}
+
+pub fn run(addr: &str, serve_path: Option<&std::path::Path>) {
+    let config = read_config();
+    let storage: Arc<Mutex<Box<dyn Storage>>> =
+        Arc::new(Mutex::new(storage::open(&config.storage).unwrap()));
 
     struct WsClient {
+        id: ClientId,
+        /// Handshake completed successfully
+        welcomed: bool,
+        /// Channels the client asked for in [ClientMessage::Hello]
+        channels: Vec<String>,
         sender: Arc<Mutex<Box<dyn geng::net::Sender<ServerMessage>>>>,
-        bot_sender: std::sync::mpsc::Sender<ClientMessage>,
+        watches: Arc<Mutex<Vec<String>>>,
+        clients: Sender,
+        bot_sender: std::sync::mpsc::Sender<(ClientId, ClientMessage)>,
+        storage: Arc<Mutex<Box<dyn Storage>>>,
     }
 
     impl Drop for WsClient {
         fn drop(&mut self) {
-            // todo!()
+            self.clients.unregister(self.id);
+        }
+    }
+
+    impl WsClient {
+        /// Run a storage operation and reply with its result or the error
+        fn storage_request(
+            &self,
+            request_id: String,
+            f: impl FnOnce(&mut dyn Storage, String) -> eyre::Result<ServerMessage>,
+        ) {
+            let result = f(&mut **self.storage.lock().unwrap(), request_id.clone());
+            let reply = result.unwrap_or_else(|e| {
+                log::error!("Storage request failed: {e}");
+                ServerMessage::Error {
+                    request_id: Some(request_id),
+                    message: e.to_string(),
+                }
+            });
+            self.sender.lock().unwrap().send(reply);
         }
     }
 
     impl geng::net::Receiver<ClientMessage> for WsClient {
         fn handle(&mut self, message: ClientMessage) {
-            fn key_file_path(key: &str) -> std::path::PathBuf {
-                std::path::Path::new("storage").join(key)
-            }
+            self.clients.seen(self.id);
             match message {
-                ClientMessage::GetKeyValue { request_id, key } => {
-                    let value = match std::fs::File::open(key_file_path(&key)) {
-                        Ok(mut file) => {
-                            let mut result = String::new();
-                            file.read_to_string(&mut result).unwrap();
-                            Some(result)
+                ClientMessage::Pong => {}
+                ClientMessage::Hello {
+                    protocol_version,
+                    channels,
+                } => {
+                    if protocol_version != PROTOCOL_VERSION {
+                        log::warn!(
+                            "Client {:?} uses protocol version {protocol_version}, but we need {PROTOCOL_VERSION}",
+                            self.id,
+                        );
+                    }
+                    let ready = protocol_version == PROTOCOL_VERSION && self.clients.is_ready();
+                    if ready && !self.welcomed {
+                        self.welcomed = true;
+                        self.channels = channels
+                            .iter()
+                            .map(|channel| channel.to_lowercase())
+                            .collect();
+                        self.clients.welcome(self.id, self.channels.clone());
+                    }
+                    let mut sender = self.sender.lock().unwrap();
+                    sender.send(ServerMessage::Welcome {
+                        protocol_version: PROTOCOL_VERSION,
+                        client_id: self.id,
+                        ready,
+                        capabilities: CAPABILITIES
+                            .iter()
+                            .map(|capability| capability.to_string())
+                            .collect(),
+                    });
+                    if ready {
+                        for message in self.clients.current_state() {
+                            sender.send(message);
                         }
-                        Err(e) => None,
-                    };
-                    self.sender
-                        .lock()
-                        .unwrap()
-                        .send(ServerMessage::KeyValue { request_id, value });
+                    }
+                }
+                _ if !self.welcomed => {
+                    self.sender.lock().unwrap().send(ServerMessage::Error {
+                        request_id: None,
+                        message: "Handshake is not complete".to_owned(),
+                    });
                 }
-                ClientMessage::SetKeyValue { key, value } => {
-                    let path = key_file_path(&key);
-                    std::fs::create_dir_all(&path.parent().unwrap()).unwrap();
-                    std::fs::File::create(path)
-                        .unwrap()
-                        .write_all(value.as_bytes())
-                        .unwrap();
+                ClientMessage::ListClients { request_id } => {
+                    // Not holding our sender lock while locking all the clients
+                    let clients = self.clients.connected_clients();
+                    self.sender.lock().unwrap().send(ServerMessage::Clients {
+                        request_id,
+                        clients,
+                    });
                 }
-                ClientMessage::Say { text, reply_to } => {
-                    self.bot_sender
-                        .send(ClientMessage::Say { text, reply_to })
-                        .unwrap();
+                ClientMessage::GetKeyValue {
+                    request_id,
+                    namespace,
+                    key,
+                } => {
+                    self.storage_request(request_id, |store, request_id| {
+                        let value = store.get(&storage::full_key(&namespace, &key)?)?;
+                        Ok(ServerMessage::KeyValue { request_id, value })
+                    });
+                }
+                ClientMessage::SetKeyValue {
+                    namespace,
+                    key,
+                    value,
+                } => {
+                    let result = storage::full_key(&namespace, &key).and_then(|key| {
+                        self.storage.lock().unwrap().set(&key, &value)?;
+                        self.clients.key_changed(&key, Some(&value));
+                        Ok(())
+                    });
+                    if let Err(e) = result {
+                        log::error!("Failed to set {key:?} in {namespace:?}: {e}");
+                        self.sender.lock().unwrap().send(ServerMessage::Error {
+                            request_id: None,
+                            message: e.to_string(),
+                        });
+                    }
+                }
+                ClientMessage::ListKeys {
+                    request_id,
+                    namespace,
+                    prefix,
+                } => {
+                    self.storage_request(request_id, |store, request_id| {
+                        let keys = store.list(&storage::full_prefix(&namespace, &prefix)?)?;
+                        let keys = keys
+                            .into_iter()
+                            .map(|key| key[namespace.len() + 1..].to_owned())
+                            .collect();
+                        Ok(ServerMessage::Keys { request_id, keys })
+                    });
+                }
+                ClientMessage::DeleteKey {
+                    request_id,
+                    namespace,
+                    key,
+                } => {
+                    self.storage_request(request_id, |store, request_id| {
+                        let key = storage::full_key(&namespace, &key)?;
+                        let existed = store.delete(&key)?;
+                        if existed {
+                            self.clients.key_changed(&key, None);
+                        }
+                        Ok(ServerMessage::KeyDeleted {
+                            request_id,
+                            existed,
+                        })
+                    });
+                }
+                ClientMessage::Increment {
+                    request_id,
+                    namespace,
+                    key,
+                    delta,
+                } => {
+                    self.storage_request(request_id, |store, request_id| {
+                        let key = storage::full_key(&namespace, &key)?;
+                        let value = store.increment(&key, delta)?;
+                        self.clients.key_changed(&key, Some(&value.to_string()));
+                        Ok(ServerMessage::Incremented { request_id, value })
+                    });
+                }
+                ClientMessage::CompareAndSwap {
+                    request_id,
+                    namespace,
+                    key,
+                    expected,
+                    value,
+                } => {
+                    self.storage_request(request_id, |store, request_id| {
+                        let key = storage::full_key(&namespace, &key)?;
+                        let result =
+                            store.compare_and_swap(&key, expected.as_deref(), value.as_deref())?;
+                        Ok(match result {
+                            Ok(()) => {
+                                self.clients.key_changed(&key, value.as_deref());
+                                ServerMessage::Swapped {
+                                    request_id,
+                                    success: true,
+                                    current: value,
+                                }
+                            }
+                            Err(current) => ServerMessage::Swapped {
+                                request_id,
+                                success: false,
+                                current,
+                            },
+                        })
+                    });
+                }
+                ClientMessage::Watch { namespace, prefix } => {
+                    match storage::full_prefix(&namespace, &prefix) {
+                        Ok(prefix) => self.watches.lock().unwrap().push(prefix),
+                        Err(e) => {
+                            self.sender.lock().unwrap().send(ServerMessage::Error {
+                                request_id: None,
+                                message: e.to_string(),
+                            });
+                        }
+                    }
+                }
+                ClientMessage::Say {
+                    text,
+                    reply_to,
+                    channel,
+                } => {
+                    // Bot picks its main channel if we don't know either
+                    let channel = channel
+                        .or_else(|| reply_to.as_ref().map(|id| id.channel.clone()))
+                        .or_else(|| self.channels.first().cloned());
+                    let message = ClientMessage::Say {
+                        text,
+                        reply_to,
+                        channel,
+                    };
+                    self.bot_sender.send((self.id, message)).unwrap();
+                }
+                message @ (ClientMessage::UpdateRedemption { .. }
+                | ClientMessage::DeleteMessage { .. }
+                | ClientMessage::Timeout { .. }
+                | ClientMessage::Ban { .. }) => {
+                    self.bot_sender.send((self.id, message)).unwrap();
                 }
             }
         }
@@ -91,12 +485,21 @@ pub fn run(addr: &str, serve_path: Option<&std::path::Path>) {
 
     struct WsApp {
         sender: Sender,
-        bot_sender: std::sync::mpsc::Sender<ClientMessage>,
+        bot_sender: std::sync::mpsc::Sender<(ClientId, ClientMessage)>,
+        storage: Arc<Mutex<Box<dyn Storage>>>,
     }
 
     impl WsApp {
-        pub fn new(sender: Sender, bot_sender: std::sync::mpsc::Sender<ClientMessage>) -> Self {
-            Self { sender, bot_sender }
+        pub fn new(
+            sender: Sender,
+            bot_sender: std::sync::mpsc::Sender<(ClientId, ClientMessage)>,
+            storage: Arc<Mutex<Box<dyn Storage>>>,
+        ) -> Self {
+            Self {
+                sender,
+                bot_sender,
+                storage,
+            }
         }
     }
 
@@ -106,10 +509,17 @@ pub fn run(addr: &str, serve_path: Option<&std::path::Path>) {
         type ClientMessage = ClientMessage;
         fn connect(&mut self, sender: Box<dyn geng::net::Sender<ServerMessage>>) -> WsClient {
             let sender = Arc::new(Mutex::new(sender));
-            self.sender.register(sender.clone());
+            let watches = Arc::new(Mutex::new(Vec::new()));
+            let id = self.sender.register(sender.clone(), watches.clone());
             WsClient {
+                id,
+                welcomed: false,
+                channels: vec![],
                 sender,
+                watches,
+                clients: self.sender.clone(),
                 bot_sender: self.bot_sender.clone(),
+                storage: self.storage.clone(),
             }
         }
     }
@@ -120,11 +530,25 @@ pub fn run(addr: &str, serve_path: Option<&std::path::Path>) {
     std::thread::spawn({
         let sender = sender.clone();
         let addr = addr.to_owned();
+        let storage = storage.clone();
         move || {
-            geng::net::Server::new(WsApp::new(sender, bot_sender), &addr).run();
+            geng::net::Server::new(WsApp::new(sender, bot_sender, storage), &addr).run();
+        }
+    });
+
+    std::thread::spawn({
+        let sender = sender.clone();
+        move || loop {
+            std::thread::sleep(PING_INTERVAL);
+            sender.heartbeat();
         }
     });
 
+    // Clients are told to wait until we are logged in
+    let ttv = ttv::Client::new(&config.channels, &config.bot_login, &config.endpoints);
+    sender.set_ready();
+    log::info!("Server is ready");
+
     // TODO: do I need this?
     #[cfg(feature = "serve")]
     std::thread::spawn({
@@ -176,6 +600,6 @@ pub fn run(addr: &str, serve_path: Option<&std::path::Path>) {
         }
     });
 
-    let bot = bot::Bot::new(config, ttv, sender, bot_receiver);
+    let bot = bot::Bot::new(config, ttv, sender, bot_receiver, storage);
     bot.run();
 }
@@ -0,0 +1,41 @@
+use super::*;
+
+#[derive(Deserialize, Default)]
+pub struct Config {
+    /// Logins that get at least this role no matter the badges
+    #[serde(default)]
+    pub allow: HashMap<String, Role>,
+    /// Logins that are never trusted with anything, even if they have badges
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl Config {
+    pub fn role(&self, user: &ChatUser) -> Role {
+        let login = user.login.as_str();
+        if self
+            .deny
+            .iter()
+            .any(|denied| denied.eq_ignore_ascii_case(login))
+        {
+            return Role::Everyone;
+        }
+        let from_badges = if user.has_badge("broadcaster") {
+            Role::Broadcaster
+        } else if user.has_badge("moderator") {
+            Role::Moderator
+        } else if user.has_badge("vip") {
+            Role::Vip
+        } else if user.has_badge("subscriber") || user.has_badge("founder") {
+            Role::Subscriber
+        } else {
+            Role::Everyone
+        };
+        let allowed = self
+            .allow
+            .iter()
+            .find(|(allowed, _)| allowed.eq_ignore_ascii_case(login))
+            .map_or(Role::Everyone, |(_, &role)| role);
+        from_badges.max(allowed)
+    }
+}
@@ -0,0 +1,111 @@
+use super::*;
+
+/// Channel point reward managed by the bot, declared in the config by a key that features match on
+#[derive(Deserialize, Clone, Debug)]
+pub struct Reward {
+    pub title: String,
+    pub cost: u64,
+    #[serde(default)]
+    pub prompt: String,
+    #[serde(default)]
+    pub input_required: bool,
+    /// Global cooldown in seconds
+    #[serde(default)]
+    pub cooldown: Option<u64>,
+}
+
+/// Make the channel's rewards match the declared ones.
+///
+/// Rewards are found by the ids we saved for their keys (`saved`), so changing the title works.
+/// Rewards we don't know the id of yet are matched by title, or created if missing.
+/// We can only touch rewards created by our own client id:
+/// those are updated, and the ones that are not declared anymore are paused
+/// (not deleted, so they can come back).
+/// A failing reward does not stop the others.
+/// Returns the keys mapped to reward ids, to be saved for the next time.
+pub async fn sync(
+    helix: &ttv::helix::Client,
+    broadcaster_id: &str,
+    rewards: &HashMap<String, Reward>,
+    saved: &HashMap<String, String>,
+) -> eyre::Result<HashMap<String, String>> {
+    let all = helix.custom_rewards(broadcaster_id).await?;
+    let manageable = helix.manageable_rewards(broadcaster_id).await?;
+    let mut ids = HashMap::new();
+    for (key, reward) in rewards {
+        let taken: Vec<&String> = ids.values().collect();
+        let existing = saved
+            .get(key)
+            .and_then(|id| all.iter().find(|existing| existing.id == *id))
+            .or_else(|| {
+                all.iter().find(|existing| {
+                    existing.title == reward.title && !taken.contains(&&existing.id)
+                })
+            });
+        let can_manage = existing
+            .map(|existing| manageable.iter().any(|reward| reward.id == existing.id))
+            .unwrap_or(true);
+        match sync_reward(helix, broadcaster_id, reward, existing, can_manage).await {
+            Ok(id) => {
+                ids.insert(key.clone(), id);
+            }
+            Err(e) => log::error!("Failed to set up reward {key:?}: {e:#}"),
+        }
+    }
+    for existing in manageable {
+        if !existing.is_paused && !ids.values().any(|id| *id == existing.id) {
+            log::info!("Pausing reward {:?}", existing.title);
+            let settings = ttv::helix::CustomRewardSettings {
+                is_paused: Some(true),
+                ..default()
+            };
+            if let Err(e) = helix
+                .update_reward(broadcaster_id, &existing.id, &settings)
+                .await
+            {
+                log::error!("Failed to pause reward {:?}: {e}", existing.title);
+            }
+        }
+    }
+    Ok(ids)
+}
+
+async fn sync_reward(
+    helix: &ttv::helix::Client,
+    broadcaster_id: &str,
+    reward: &Reward,
+    existing: Option<&ttv::helix::CustomReward>,
+    can_manage: bool,
+) -> eyre::Result<String> {
+    let settings = ttv::helix::CustomRewardSettings {
+        title: Some(reward.title.clone()),
+        cost: Some(reward.cost),
+        prompt: Some(reward.prompt.clone()),
+        is_user_input_required: Some(reward.input_required),
+        is_global_cooldown_enabled: Some(reward.cooldown.is_some()),
+        global_cooldown_seconds: reward.cooldown,
+        is_paused: Some(false),
+    };
+    Ok(match existing {
+        Some(existing) if can_manage => {
+            helix
+                .update_reward(broadcaster_id, &existing.id, &settings)
+                .await?;
+            existing.id.clone()
+        }
+        // Still usable, features get its redemptions, but the bot can not manage it
+        Some(existing) => {
+            log::warn!(
+                "Reward {:?} was created in the dashboard, so it can not be updated \
+                and its redemptions can not be fulfilled or refunded. \
+                Delete it and restart to let the bot create its own",
+                existing.title,
+            );
+            existing.id.clone()
+        }
+        None => {
+            log::info!("Creating reward {:?}", reward.title);
+            helix.create_reward(broadcaster_id, &settings).await?.id
+        }
+    })
+}
@@ -9,6 +9,29 @@ pub struct DbConfig {
 pub struct TtvConfig {
     pub client_id: String,
     pub client_secret: String,
+    /// Where twitch sends the browser after logging in, must be registered for the app
+    #[serde(default = "default_redirect_uri")]
+    pub redirect_uri: String,
+    /// Where to listen for that redirect (like `0.0.0.0:3000` in a container),
+    /// by default the port of the redirect uri on localhost
+    #[serde(default)]
+    pub redirect_listen_addr: Option<std::net::SocketAddr>,
+}
+
+fn default_redirect_uri() -> String {
+    "http://localhost:3000".to_owned()
+}
+
+impl TtvConfig {
+    pub fn redirect_listen_addr(&self) -> std::net::SocketAddr {
+        self.redirect_listen_addr.unwrap_or_else(|| {
+            let port = reqwest::Url::parse(&self.redirect_uri)
+                .ok()
+                .and_then(|url| url.port_or_known_default())
+                .unwrap_or(3000);
+            std::net::SocketAddr::from(([127, 0, 0, 1], port))
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -17,8 +40,109 @@ pub struct Config {
     pub ttv: TtvConfig,
 }
 
+/// What a twitch account is used for, decides which scopes its tokens need
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Account {
+    /// Reads and writes chat and moderates it
+    Bot,
+    /// Owns the channel, channel points need the broadcaster's own authorization
+    Broadcaster,
+}
+
+impl Account {
+    /// What the user is used for, given the bot login and the channels the bot joins
+    pub fn of(login: &str, bot_login: &str, channels: &[String]) -> Vec<Self> {
+        let mut accounts = Vec::new();
+        if login == bot_login {
+            accounts.push(Self::Bot);
+        }
+        if channels.iter().any(|channel| channel == login) {
+            accounts.push(Self::Broadcaster);
+        }
+        accounts
+    }
+
+    /// Scopes needed for all the given uses
+    pub fn scopes(accounts: &[Self]) -> Vec<&'static str> {
+        let mut scopes = Vec::new();
+        for account in accounts {
+            scopes.extend_from_slice(match account {
+                Self::Bot => &[
+                    "chat:edit",
+                    "chat:read",
+                    "moderator:read:followers",
+                    "moderator:manage:banned_users",
+                    "moderator:manage:chat_messages",
+                ][..],
+                Self::Broadcaster => &["channel:read:redemptions", "channel:manage:redemptions"],
+            });
+        }
+        scopes
+    }
+}
+
+/// Where Docker mounts secrets
+const DOCKER_SECRETS_PATH: &str = "/run/secrets";
+
+/// Config values that can be set with [lookup] instead of the config file, as section and key
+const CONFIG_OVERRIDES: &[(&str, &str)] = &[("ttv", "client_id"), ("ttv", "client_secret")];
+
+/// Value from an environment variable (`TTV_CLIENT_ID`), or else a Docker secret (`ttv_client_id`)
+fn lookup(docker_path: &std::path::Path, name: &str) -> Option<String> {
+    if let Ok(value) = std::env::var(name.to_uppercase()) {
+        return Some(value);
+    }
+    read_file(docker_path.join(name))
+        .ok()
+        .map(|value| value.trim().to_owned())
+}
+
+/// Tokens are as good as a password, so only the owner can read the file
+pub fn write_tokens(path: &std::path::Path, tokens: &ttv::auth::Tokens) -> eyre::Result<()> {
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options.open(path)?;
+    // The mode is only used for new files, older ones could still be readable by anyone
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    serde_json::to_writer_pretty(file, tokens)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_write_tokens_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+    let path = std::env::temp_dir()
+        .join(format!("ttv-test-{}", std::process::id()))
+        .join("tokens.json");
+    // Even a file that was readable by anyone before
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, "").unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+    let tokens = ttv::auth::Tokens {
+        access_token: "access".to_owned(),
+        refresh_token: "refresh".to_owned(),
+        scope: vec![],
+    };
+    write_tokens(&path, &tokens).unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
 pub struct Secrets {
     path: std::path::PathBuf,
+    docker_path: std::path::PathBuf,
     pub config: Config,
 }
 
@@ -26,47 +150,140 @@ impl Secrets {
     pub fn init_from(path: impl AsRef<std::path::Path>) -> eyre::Result<Self> {
         log::debug!("Initializing secrets");
         let path = path.as_ref().to_owned();
-        let config: Config = toml::from_str(&read_file(path.join("config.toml"))?)?;
+        let docker_path = std::path::PathBuf::from(DOCKER_SECRETS_PATH);
+        // The file is optional if everything is set with environment variables or Docker secrets
+        let config_path = path.join("config.toml");
+        let mut config: toml::value::Table = if config_path.exists() {
+            toml::from_str(&read_file(config_path)?)?
+        } else {
+            toml::value::Table::new()
+        };
+        for &(section, key) in CONFIG_OVERRIDES {
+            let name = format!("{section}_{key}");
+            if let Some(value) = lookup(&docker_path, &name) {
+                log::debug!("Using {name} from the environment");
+                config
+                    .entry(section)
+                    .or_insert_with(|| toml::Value::Table(toml::value::Table::new()))
+                    .as_table_mut()
+                    .ok_or_else(|| eyre::eyre!("{section} must be a table"))?
+                    .insert(key.to_owned(), toml::Value::String(value));
+            }
+        }
+        let config: Config = toml::Value::Table(config).try_into()?;
         log::debug!("Secrets are all set up");
-        Ok(Self { path, config })
+        Ok(Self {
+            path,
+            docker_path,
+            config,
+        })
     }
     pub fn init() -> eyre::Result<Self> {
-        Self::init_from("secret")
+        Self::init_from(std::env::var("SECRETS_PATH").unwrap_or_else(|_| "secret".to_owned()))
     }
-    pub fn ttv_access_token(&self, login: impl AsRef<str>) -> eyre::Result<String> {
-        let login = login.as_ref();
-        log::debug!("Getting ttv token for {:?}", login);
-        let tokens_file_path = self.path.join("tokens").join(format!("{}.json", login));
-        let tokens: ttv::auth::Tokens = match std::fs::File::open(&tokens_file_path) {
-            Ok(file) => {
-                log::debug!("Reading existing tokens");
-                let tokens: ttv::auth::Tokens = serde_json::from_reader(file)?;
-                if block_on(ttv::auth::validate(&tokens.access_token))? {
-                    log::debug!("Token still valid");
-                    tokens
-                } else {
-                    log::debug!("Token invalid, refreshing");
-                    block_on(ttv::auth::refresh(
-                        &self.config.ttv.client_id,
-                        &self.config.ttv.client_secret,
-                        &tokens.refresh_token,
-                    ))?
-                }
-            }
-            Err(_) => {
-                log::info!("Auth not setup, prepare to login as {:?}", login);
-                block_on(ttv::auth::authenticate(
-                    &self.config.ttv.client_id,
-                    &self.config.ttv.client_secret,
-                    true,
-                    &["channel:read:redemptions", "chat:edit", "chat:read"]
-                        .map(ttv::auth::Scope::new),
-                ))?
-            }
+    fn tokens_file_path(&self, login: &str) -> std::path::PathBuf {
+        self.path.join("tokens").join(format!("{}.json", login))
+    }
+    fn save_tokens(&self, login: &str, tokens: &ttv::auth::Tokens) -> eyre::Result<()> {
+        write_tokens(&self.tokens_file_path(login), tokens)
+    }
+    /// Saved tokens of the user, or else pre-issued ones as json in `TTV_TOKENS_<LOGIN>`.
+    /// Saved ones come first since refreshed tokens are saved.
+    fn stored_tokens(&self, login: &str) -> eyre::Result<ttv::auth::Tokens> {
+        if let Ok(json) = read_file(self.tokens_file_path(login)) {
+            return Ok(serde_json::from_str(&json)?);
+        }
+        let name = format!("ttv_tokens_{login}");
+        match lookup(&self.docker_path, &name) {
+            Some(json) => Ok(serde_json::from_str(&json)?),
+            None => eyre::bail!(
+                "No tokens for {login:?}, authorize with `--authorize {login}` or set {}",
+                name.to_uppercase(),
+            ),
+        }
+    }
+    /// Log in as the user and save the tokens, replacing the old ones.
+    /// With `device_code` the login happens on another device instead of the browser here.
+    pub fn authorize(
+        &self,
+        login: &str,
+        accounts: &[Account],
+        device_code: bool,
+    ) -> eyre::Result<()> {
+        let scopes = Account::scopes(accounts);
+        log::info!("Prepare to login as {login:?}, it needs {scopes:?}");
+        let scopes: Vec<_> = scopes.iter().map(ttv::auth::Scope::new).collect();
+        let tokens = if device_code {
+            block_on(ttv::auth::authenticate_device(
+                &self.config.ttv.client_id,
+                &scopes,
+            ))?
+        } else {
+            block_on(ttv::auth::authenticate(
+                &self.config.ttv.client_id,
+                &self.config.ttv.client_secret,
+                true,
+                &self.config.ttv.redirect_uri,
+                self.config.ttv.redirect_listen_addr(),
+                &scopes,
+            ))?
         };
-        std::fs::create_dir_all(tokens_file_path.parent().unwrap())?;
-        serde_json::to_writer_pretty(std::fs::File::create(&tokens_file_path)?, &tokens)?;
+        // Easy to be logged in as someone else in the browser
+        let validation = block_on(ttv::auth::validate(&tokens.access_token))?
+            .ok_or_else(|| eyre::eyre!("Got an invalid token"))?;
+        if validation.login != login {
+            eyre::bail!("Logged in as {:?} instead of {login:?}", validation.login);
+        }
+        self.save_tokens(login, &tokens)
+    }
+    /// Valid tokens of the user and seconds until they expire
+    fn ttv_tokens(
+        &self,
+        login: &str,
+        accounts: &[Account],
+    ) -> eyre::Result<(ttv::auth::Tokens, u64)> {
+        log::debug!("Getting ttv token for {:?}", login);
+        let tokens = self.stored_tokens(login)?;
+        let missing: Vec<&str> = Account::scopes(accounts)
+            .into_iter()
+            .filter(|&scope| !tokens.scope.iter().any(|have| have.as_str() == scope))
+            .collect();
+        if !missing.is_empty() {
+            eyre::bail!(
+                "Tokens of {login:?} are missing scopes {missing:?}, \
+                authorize again with `--authorize {login}`",
+            );
+        }
+        if let Some(validation) = block_on(ttv::auth::validate(&tokens.access_token))? {
+            log::debug!("Token still valid");
+            return Ok((tokens, validation.expires_in));
+        }
+        log::debug!("Token invalid, refreshing");
+        let tokens = block_on(ttv::auth::refresh(
+            &self.config.ttv.client_id,
+            &self.config.ttv.client_secret,
+            &tokens.refresh_token,
+        ))?;
+        let validation = block_on(ttv::auth::validate(&tokens.access_token))?
+            .ok_or_else(|| eyre::eyre!("Refreshed token of {login:?} is invalid"))?;
+        self.save_tokens(login, &tokens)?;
         log::debug!("Token retrieved successfully");
-        Ok(tokens.access_token)
+        Ok((tokens, validation.expires_in))
+    }
+    /// Token that keeps itself fresh, for long running things
+    pub fn ttv_credentials(
+        &self,
+        login: &str,
+        accounts: &[Account],
+    ) -> eyre::Result<ttv::Credentials> {
+        let (tokens, expires_in) = self.ttv_tokens(login, accounts)?;
+        Ok(ttv::Credentials::new(
+            login,
+            &self.config.ttv.client_id,
+            &self.config.ttv.client_secret,
+            self.tokens_file_path(login),
+            tokens,
+            std::time::Duration::from_secs(expires_in),
+        ))
     }
 }
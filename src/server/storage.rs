@@ -0,0 +1,505 @@
+use super::*;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Config {
+    /// Every key is a separate file inside the directory
+    Directory { path: std::path::PathBuf },
+    /// Single file embedded database
+    Sqlite {
+        path: std::path::PathBuf,
+        /// Directory storage to import into the database on first start
+        #[serde(default = "default_migrate_from")]
+        migrate_from: Option<std::path::PathBuf>,
+    },
+}
+
+fn default_migrate_from() -> Option<std::path::PathBuf> {
+    Some("storage".into())
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::Directory {
+            path: "storage".into(),
+        }
+    }
+}
+
+fn validate_namespace(namespace: &str) -> eyre::Result<()> {
+    const MAX_NAMESPACE_LEN: usize = 64;
+    if namespace.is_empty() || namespace.len() > MAX_NAMESPACE_LEN {
+        eyre::bail!("Invalid namespace length");
+    }
+    if !namespace
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        eyre::bail!("Invalid namespace {namespace:?}");
+    }
+    Ok(())
+}
+
+fn validate_key(key: &str) -> eyre::Result<()> {
+    const MAX_KEY_LEN: usize = 256;
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        eyre::bail!("Invalid key length");
+    }
+    for segment in key.split('/') {
+        if segment.is_empty() || segment.starts_with('.') || segment.ends_with(".tmp") {
+            eyre::bail!("Invalid key {key:?}");
+        }
+        if !segment
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '%'))
+        {
+            eyre::bail!("Invalid key {key:?}");
+        }
+    }
+    Ok(())
+}
+
+/// Validate client supplied namespace and key and join them into a storage key.
+///
+/// Namespaces are a single lowercase segment (usually the feature name),
+/// keys are `/` separated relative paths of plain segments,
+/// so nothing can escape its namespace or the storage root.
+pub fn full_key(namespace: &str, key: &str) -> eyre::Result<String> {
+    validate_namespace(namespace)?;
+    validate_key(key)?;
+    Ok(format!("{namespace}/{key}"))
+}
+
+/// Same as [full_key] but for key prefixes, which may be empty or end with a `/`
+pub fn full_prefix(namespace: &str, prefix: &str) -> eyre::Result<String> {
+    validate_namespace(namespace)?;
+    if !prefix.is_empty() {
+        validate_key(prefix.strip_suffix('/').unwrap_or(prefix))?;
+    }
+    Ok(format!("{namespace}/{prefix}"))
+}
+
+pub trait Storage: Send {
+    fn get(&mut self, key: &str) -> eyre::Result<Option<String>>;
+    fn set(&mut self, key: &str, value: &str) -> eyre::Result<()>;
+    /// Returns whether the key existed
+    fn delete(&mut self, key: &str) -> eyre::Result<bool>;
+    /// All keys starting with the prefix, sorted
+    fn list(&mut self, prefix: &str) -> eyre::Result<Vec<String>>;
+
+    /// Add `delta` to an integer value (missing value is treated as 0) and return the result
+    fn increment(&mut self, key: &str, delta: i64) -> eyre::Result<i64> {
+        let value = parse_integer(key, self.get(key)?)? + delta;
+        self.set(key, &value.to_string())?;
+        Ok(value)
+    }
+
+    /// Replace the value (`None` meaning absent) only if the current one is equal to `expected`.
+    /// Returns the current value if it did not match.
+    fn compare_and_swap(
+        &mut self,
+        key: &str,
+        expected: Option<&str>,
+        value: Option<&str>,
+    ) -> eyre::Result<Result<(), Option<String>>> {
+        let current = self.get(key)?;
+        if current.as_deref() != expected {
+            return Ok(Err(current));
+        }
+        match value {
+            Some(value) => self.set(key, value)?,
+            None => {
+                self.delete(key)?;
+            }
+        }
+        Ok(Ok(()))
+    }
+}
+
+fn parse_integer(key: &str, value: Option<String>) -> eyre::Result<i64> {
+    match value {
+        Some(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| eyre::eyre!("Value of {key:?} is not an integer")),
+        None => Ok(0),
+    }
+}
+
+pub fn open(config: &Config) -> eyre::Result<Box<dyn Storage>> {
+    Ok(match config {
+        Config::Directory { path } => Box::new(DirectoryStorage::new(path)),
+        Config::Sqlite { path, migrate_from } => {
+            let mut storage = SqliteStorage::open(path)?;
+            if let Some(dir) = migrate_from {
+                storage.migrate_from(dir)?;
+            }
+            Box::new(storage)
+        }
+    })
+}
+
+pub struct DirectoryStorage {
+    root: std::path::PathBuf,
+}
+
+impl DirectoryStorage {
+    pub fn new(root: impl AsRef<std::path::Path>) -> Self {
+        Self {
+            root: root.as_ref().to_owned(),
+        }
+    }
+
+    fn key_file_path(&self, key: &str) -> std::path::PathBuf {
+        self.root.join(key)
+    }
+
+    /// Collect all keys (relative file paths) stored in the directory
+    fn keys(&self) -> eyre::Result<Vec<String>> {
+        fn visit(
+            dir: &std::path::Path,
+            prefix: &str,
+            result: &mut Vec<String>,
+        ) -> eyre::Result<()> {
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let Some(name) = entry.file_name().to_str().map(|name| name.to_owned()) else {
+                    log::warn!("Skipping non utf-8 path {:?}", entry.path());
+                    continue;
+                };
+                let key = format!("{prefix}{name}");
+                if entry.file_type()?.is_dir() {
+                    visit(&entry.path(), &format!("{key}/"), result)?;
+                } else if !name.ends_with(".tmp") {
+                    result.push(key);
+                }
+            }
+            Ok(())
+        }
+        let mut result = Vec::new();
+        if self.root.is_dir() {
+            visit(&self.root, "", &mut result)?;
+        }
+        Ok(result)
+    }
+}
+
+impl Storage for DirectoryStorage {
+    fn get(&mut self, key: &str) -> eyre::Result<Option<String>> {
+        match std::fs::read_to_string(self.key_file_path(key)) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> eyre::Result<()> {
+        let path = self.key_file_path(key);
+        let dir = path
+            .parent()
+            .ok_or_else(|| eyre::eyre!("Key {key:?} has no parent directory"))?;
+        std::fs::create_dir_all(dir)?;
+        // Write to a temporary file first and then rename it,
+        // so that a crash in the middle never leaves a half-written value
+        let tmp_path = path.with_file_name(format!(
+            "{}.tmp",
+            path.file_name().unwrap().to_string_lossy()
+        ));
+        {
+            let mut file = std::fs::File::create(&tmp_path)?;
+            file.write_all(value.as_bytes())?;
+            file.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> eyre::Result<bool> {
+        match std::fs::remove_file(self.key_file_path(key)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn list(&mut self, prefix: &str) -> eyre::Result<Vec<String>> {
+        let mut keys: Vec<String> = self
+            .keys()?
+            .into_iter()
+            .filter(|key| key.starts_with(prefix))
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+pub struct SqliteStorage {
+    connection: rusqlite::Connection,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<std::path::Path>) -> eyre::Result<Self> {
+        let connection = rusqlite::Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS key_value (
+                key TEXT PRIMARY KEY NOT NULL,
+                value TEXT NOT NULL
+            )",
+            (),
+        )?;
+        Ok(Self { connection })
+    }
+
+    /// Import everything from the directory storage in a single transaction.
+    /// Keys that already exist in the database are left untouched.
+    /// After a successful import the directory is renamed so this only happens once.
+    pub fn migrate_from(&mut self, dir: impl AsRef<std::path::Path>) -> eyre::Result<()> {
+        let dir = dir.as_ref();
+        if !dir.is_dir() {
+            return Ok(());
+        }
+        log::info!("Migrating key value storage from {dir:?}");
+        let mut source = DirectoryStorage::new(dir);
+        let keys = source.keys()?;
+        let transaction = self.connection.transaction()?;
+        for key in &keys {
+            let Some(value) = source.get(key)? else { continue };
+            transaction.execute(
+                "INSERT OR IGNORE INTO key_value (key, value) VALUES (?1, ?2)",
+                (key, &value),
+            )?;
+        }
+        transaction.commit()?;
+        let mut migrated = dir.as_os_str().to_owned();
+        migrated.push(".migrated");
+        std::fs::rename(dir, &migrated)?;
+        log::info!(
+            "Migrated {} keys, old storage moved to {migrated:?}",
+            keys.len()
+        );
+        Ok(())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn get(&mut self, key: &str) -> eyre::Result<Option<String>> {
+        use rusqlite::OptionalExtension;
+        Ok(self
+            .connection
+            .query_row("SELECT value FROM key_value WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> eyre::Result<()> {
+        self.connection.execute(
+            "INSERT INTO key_value (key, value) VALUES (?1, ?2)
+            ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            (key, value),
+        )?;
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> eyre::Result<bool> {
+        let deleted = self
+            .connection
+            .execute("DELETE FROM key_value WHERE key = ?1", [key])?;
+        Ok(deleted != 0)
+    }
+
+    fn list(&mut self, prefix: &str) -> eyre::Result<Vec<String>> {
+        // Not using LIKE since keys may contain wildcard characters
+        let mut statement = self.connection.prepare(
+            "SELECT key FROM key_value WHERE substr(key, 1, length(?1)) = ?1 ORDER BY key",
+        )?;
+        let keys = statement
+            .query_map([prefix], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(keys)
+    }
+
+    fn increment(&mut self, key: &str, delta: i64) -> eyre::Result<i64> {
+        use rusqlite::OptionalExtension;
+        let transaction = self.connection.transaction()?;
+        let current = transaction
+            .query_row("SELECT value FROM key_value WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let value = parse_integer(key, current)? + delta;
+        transaction.execute(
+            "INSERT INTO key_value (key, value) VALUES (?1, ?2)
+            ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            (key, value.to_string()),
+        )?;
+        transaction.commit()?;
+        Ok(value)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: &str,
+        expected: Option<&str>,
+        value: Option<&str>,
+    ) -> eyre::Result<Result<(), Option<String>>> {
+        use rusqlite::OptionalExtension;
+        let transaction = self.connection.transaction()?;
+        let current: Option<String> = transaction
+            .query_row("SELECT value FROM key_value WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        if current.as_deref() != expected {
+            return Ok(Err(current));
+        }
+        match value {
+            Some(value) => transaction.execute(
+                "INSERT INTO key_value (key, value) VALUES (?1, ?2)
+                ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+                (key, value),
+            )?,
+            None => transaction.execute("DELETE FROM key_value WHERE key = ?1", [key])?,
+        };
+        transaction.commit()?;
+        Ok(Ok(()))
+    }
+}
+
+#[test]
+fn test_full_key() {
+    assert_eq!(
+        full_key("raffle_royale", "kuviman/level.json").unwrap(),
+        "raffle_royale/kuviman/level.json",
+    );
+    assert!(full_key("avatars", "Some_Name-123.json").is_ok());
+    // Localized display names are percent encoded by the client
+    assert!(full_key("raffle_royale", "%E3%83%8B%E3%83%A3%E3%83%B3/level.json").is_ok());
+    assert!(full_key("raffle_royale", "ニャン/level.json").is_err());
+    assert!(full_key("", "key").is_err());
+    assert!(full_key("../avatars", "key").is_err());
+    assert!(full_key("avatars/x", "key").is_err());
+    assert!(full_key("avatars", "").is_err());
+    assert!(full_key("avatars", "../config.json").is_err());
+    assert!(full_key("avatars", "a/../../config.json").is_err());
+    assert!(full_key("avatars", "/etc/passwd").is_err());
+    assert!(full_key("avatars", "a//b").is_err());
+    assert!(full_key("avatars", "a/").is_err());
+    assert!(full_key("avatars", ".hidden").is_err());
+    assert!(full_key("avatars", "a\\b").is_err());
+    assert!(full_key("avatars", "name.json.tmp").is_err());
+    assert!(full_key("avatars", "C:").is_err());
+    assert_eq!(full_prefix("avatars", "").unwrap(), "avatars/");
+    assert_eq!(full_prefix("avatars", "kuvi").unwrap(), "avatars/kuvi");
+    assert_eq!(
+        full_prefix("avatars", "kuviman/").unwrap(),
+        "avatars/kuviman/"
+    );
+    assert!(full_prefix("avatars", "/").is_err());
+    assert!(full_prefix("avatars", "../").is_err());
+}
+
+/// Fresh directory for a test to play in
+#[cfg(test)]
+fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("storage-test-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_sqlite_storage() {
+    let dir = test_dir("sqlite");
+    let mut storage = SqliteStorage::open(dir.join("storage.db")).unwrap();
+    assert_eq!(storage.get("a/x.json").unwrap(), None);
+    storage.set("a/x.json", "1").unwrap();
+    storage.set("a/y.json", "\"y\"").unwrap();
+    storage.set("b.json", "true").unwrap();
+    storage.set("a/x.json", "2").unwrap();
+    assert_eq!(storage.get("a/x.json").unwrap().as_deref(), Some("2"));
+    assert_eq!(storage.list("a/").unwrap(), ["a/x.json", "a/y.json"]);
+    // Wildcards are not special
+    assert!(storage.list("a%").unwrap().is_empty());
+
+    assert_eq!(storage.increment("a/x.json", 3).unwrap(), 5);
+    assert_eq!(storage.increment("counter.json", -1).unwrap(), -1);
+    assert!(storage.increment("a/y.json", 1).is_err());
+
+    assert_eq!(
+        storage
+            .compare_and_swap("b.json", Some("false"), Some("x"))
+            .unwrap(),
+        Err(Some("true".to_owned())),
+    );
+    assert_eq!(
+        storage
+            .compare_and_swap("b.json", Some("true"), None)
+            .unwrap(),
+        Ok(()),
+    );
+    assert_eq!(
+        storage
+            .compare_and_swap("b.json", None, Some("new"))
+            .unwrap(),
+        Ok(()),
+    );
+    assert_eq!(storage.get("b.json").unwrap().as_deref(), Some("new"));
+
+    assert!(storage.delete("a/y.json").unwrap());
+    assert!(!storage.delete("a/y.json").unwrap());
+
+    // Everything was committed to the file
+    drop(storage);
+    let mut storage = SqliteStorage::open(dir.join("storage.db")).unwrap();
+    assert_eq!(storage.get("a/x.json").unwrap().as_deref(), Some("5"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_migrate_from_directory() {
+    let dir = test_dir("migrate");
+    let old = dir.join("storage");
+    let mut directory = DirectoryStorage::new(&old);
+    directory
+        .set("avatars/custom/kuviman.json", "\"crab\"")
+        .unwrap();
+    directory
+        .set("raffle_royale/kuviman/level.json", "3")
+        .unwrap();
+    directory.set("hello.json", "\"old\"").unwrap();
+    // Leftover of a crashed write
+    std::fs::write(old.join("hello.json.tmp"), "garbage").unwrap();
+
+    let mut storage = SqliteStorage::open(dir.join("storage.db")).unwrap();
+    storage.set("hello.json", "\"new\"").unwrap();
+    storage.migrate_from(&old).unwrap();
+    assert_eq!(
+        storage.list("").unwrap(),
+        [
+            "avatars/custom/kuviman.json",
+            "hello.json",
+            "raffle_royale/kuviman/level.json",
+        ],
+    );
+    assert_eq!(
+        storage
+            .get("raffle_royale/kuviman/level.json")
+            .unwrap()
+            .as_deref(),
+        Some("3"),
+    );
+    // Existing keys win
+    assert_eq!(
+        storage.get("hello.json").unwrap().as_deref(),
+        Some("\"new\"")
+    );
+
+    // Only happens once
+    assert!(!old.exists());
+    assert!(dir.join("storage.migrated").is_dir());
+    storage.migrate_from(&old).unwrap();
+    assert_eq!(storage.list("").unwrap().len(), 3);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
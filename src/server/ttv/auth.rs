@@ -12,24 +12,38 @@ impl Scope {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize)]
 pub struct Tokens {
     pub access_token: String,
     pub refresh_token: String,
     pub scope: Vec<Scope>,
 }
 
+// Keeping the tokens out of the logs
+impl std::fmt::Debug for Tokens {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tokens")
+            .field("access_token", &"<redacted>")
+            .field("refresh_token", &"<redacted>")
+            .field("scope", &self.scope)
+            .finish()
+    }
+}
+
 /// Authenticate using authorization code grant flow
 /// <https://dev.twitch.tv/docs/authentication/getting-tokens-oauth#authorization-code-grant-flow>
+///
+/// The user is redirected to `redirect_uri` (same as specified in the twitch registered app),
+/// which should reach us at `listen_addr`
 pub async fn authenticate(
     client_id: &str,
     client_secret: &str,
     force_verify: bool,
+    redirect_uri: &str,
+    listen_addr: std::net::SocketAddr,
     scopes: &[Scope],
 ) -> eyre::Result<Tokens> {
-    // We will redirect user to this url to retrieve the token
-    // This url should be same as specified in the twitch registered app
-    let redirect_uri = "http://localhost:3000";
+    let redirect_url = Url::parse(redirect_uri)?;
 
     // From twitch docs:
     // Although optional, you are strongly encouraged to pass a state string to
@@ -65,10 +79,13 @@ pub async fn authenticate(
     }
 
     log::info!("Opening {}", authorize_url);
-    open::that(authorize_url.as_str())?; // Open browser
+    // There may be no browser, then the url can be opened elsewhere
+    if let Err(e) = open::that(authorize_url.as_str()) {
+        log::warn!("Failed to open the browser, open the url yourself: {e}");
+    }
 
     log::debug!("Waiting for the user to be redirected to {}", redirect_uri);
-    let redirected_url = wait_for_request_uri().await?;
+    let redirected_url = wait_for_request_uri(listen_addr, &redirect_url).await?;
     let query: HashMap<_, _> = redirected_url.query_pairs().collect();
 
     if **query.get("state").expect("Expected to see state") != state {
@@ -114,11 +131,81 @@ fn test_authenticate() {
             &secrets.config.ttv.client_id,
             &secrets.config.ttv.client_secret,
             true,
+            &secrets.config.ttv.redirect_uri,
+            secrets.config.ttv.redirect_listen_addr(),
             &[Scope::new("channel:read:redemptions")],
         )),
     );
 }
 
+#[derive(Deserialize)]
+struct DeviceCode {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    /// Seconds until the code expires
+    expires_in: u64,
+    /// Seconds to wait between polling for the token
+    interval: u64,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    message: String,
+}
+
+/// Authenticate using device code grant flow, the user logs in on any other device,
+/// so this works on a headless machine
+/// <https://dev.twitch.tv/docs/authentication/getting-tokens-oauth#device-code-grant-flow>
+pub async fn authenticate_device(client_id: &str, scopes: &[Scope]) -> eyre::Result<Tokens> {
+    let scopes = scopes
+        .iter()
+        .map(|scope| scope.as_str())
+        .collect::<Vec<&str>>()
+        .join(" ");
+    let client = reqwest::Client::new();
+    let mut form = HashMap::new();
+    form.insert("client_id", client_id);
+    form.insert("scopes", &scopes);
+    let code: DeviceCode = client
+        .post("https://id.twitch.tv/oauth2/device")
+        .form(&form)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    log::info!(
+        "Go to {} on any device and enter the code {}",
+        code.verification_uri,
+        code.user_code,
+    );
+
+    form.insert("device_code", &code.device_code);
+    form.insert("grant_type", "urn:ietf:params:oauth:grant-type:device_code");
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(code.expires_in);
+    let mut interval = std::time::Duration::from_secs(code.interval);
+    while std::time::Instant::now() < deadline {
+        tokio::time::sleep(interval).await;
+        let response = client
+            .post("https://id.twitch.tv/oauth2/token")
+            .form(&form)
+            .send()
+            .await?;
+        if response.status().is_success() {
+            log::debug!("Got the token");
+            return Ok(response.json().await?);
+        }
+        let error: ErrorResponse = response.json().await?;
+        match error.message.as_str() {
+            "authorization_pending" => {}
+            "slow_down" => interval += std::time::Duration::from_secs(5),
+            message => eyre::bail!("Device code login failed: {message}"),
+        }
+    }
+    eyre::bail!("The code expired before the login was completed")
+}
+
 pub async fn refresh(
     client_id: &str,
     client_secret: &str,
@@ -138,15 +225,23 @@ pub async fn refresh(
         .await?)
 }
 
-pub async fn validate(token: &str) -> eyre::Result<bool> {
+#[derive(Deserialize, Debug)]
+pub struct Validation {
+    pub login: String,
+    /// Seconds until the token expires
+    pub expires_in: u64,
+}
+
+/// Returns `None` if the token is invalid
+pub async fn validate(token: &str) -> eyre::Result<Option<Validation>> {
     let response = reqwest::Client::new()
         .get("https://id.twitch.tv/oauth2/validate")
         .header("Authorization", format!("OAuth {}", token))
         .send()
         .await?;
     match response.status() {
-        reqwest::StatusCode::OK => Ok(true),
-        reqwest::StatusCode::UNAUTHORIZED => Ok(false),
+        reqwest::StatusCode::OK => Ok(Some(response.json().await?)),
+        reqwest::StatusCode::UNAUTHORIZED => Ok(None),
         _ => eyre::bail!("Unexpected status {}", response.status()),
     }
 }
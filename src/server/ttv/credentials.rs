@@ -0,0 +1,169 @@
+use super::*;
+
+use twitch_irc::login::{CredentialsPair, LoginCredentials};
+
+/// Refresh the token this long before it expires
+const REFRESH_MARGIN: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+/// Twitch wants apps to validate their tokens every hour
+const VALIDATE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+/// Wait before trying again after a failed background refresh
+const RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Token of a twitch user that is refreshed before it expires and saved back to the secrets.
+/// Clones share the token, so irc, EventSub and helix all use the same one.
+#[derive(Clone)]
+pub struct Credentials {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    login: String,
+    client_id: String,
+    client_secret: String,
+    /// Where the tokens are saved
+    path: std::path::PathBuf,
+    state: tokio::sync::Mutex<State>,
+}
+
+struct State {
+    tokens: auth::Tokens,
+    expires_at: std::time::Instant,
+}
+
+// Keeping the tokens out of the logs
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("login", &self.inner.login)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Credentials {
+    pub fn new(
+        login: &str,
+        client_id: &str,
+        client_secret: &str,
+        path: impl AsRef<std::path::Path>,
+        tokens: auth::Tokens,
+        expires_in: std::time::Duration,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                login: login.to_owned(),
+                client_id: client_id.to_owned(),
+                client_secret: client_secret.to_owned(),
+                path: path.as_ref().to_owned(),
+                state: tokio::sync::Mutex::new(State {
+                    tokens,
+                    expires_at: std::time::Instant::now() + expires_in,
+                }),
+            }),
+        }
+    }
+
+    pub fn login(&self) -> &str {
+        &self.inner.login
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.inner.client_id
+    }
+
+    /// Current token, refreshed first if it is about to expire
+    pub async fn access_token(&self) -> eyre::Result<String> {
+        let mut state = self.inner.state.lock().await;
+        if state
+            .expires_at
+            .saturating_duration_since(std::time::Instant::now())
+            < REFRESH_MARGIN
+        {
+            self.refresh_locked(&mut state).await?;
+        }
+        Ok(state.tokens.access_token.clone())
+    }
+
+    /// Get a new token even though the current one did not expire yet (because it was rejected)
+    pub async fn refresh(&self) -> eyre::Result<String> {
+        let mut state = self.inner.state.lock().await;
+        self.refresh_locked(&mut state).await?;
+        Ok(state.tokens.access_token.clone())
+    }
+
+    async fn refresh_locked(&self, state: &mut State) -> eyre::Result<()> {
+        log::info!("Refreshing the token of {:?}", self.inner.login);
+        let tokens = auth::refresh(
+            &self.inner.client_id,
+            &self.inner.client_secret,
+            &state.tokens.refresh_token,
+        )
+        .await?;
+        let validation = auth::validate(&tokens.access_token)
+            .await?
+            .ok_or_else(|| eyre::eyre!("Refreshed token is invalid"))?;
+        secret::write_tokens(&self.inner.path, &tokens)?;
+        *state = State {
+            tokens,
+            expires_at: std::time::Instant::now()
+                + std::time::Duration::from_secs(validation.expires_in),
+        };
+        Ok(())
+    }
+
+    /// Validate the token hourly and refresh it before it expires, never returns
+    pub async fn keep_fresh(self) {
+        loop {
+            let wait = {
+                let state = self.inner.state.lock().await;
+                state
+                    .expires_at
+                    .saturating_duration_since(std::time::Instant::now())
+                    .saturating_sub(REFRESH_MARGIN)
+                    .min(VALIDATE_INTERVAL)
+            };
+            tokio::time::sleep(wait).await;
+            if let Err(e) = self.check().await {
+                log::error!(
+                    "Failed to refresh the token of {:?}: {e:#}",
+                    self.inner.login,
+                );
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+
+    async fn check(&self) -> eyre::Result<()> {
+        let mut state = self.inner.state.lock().await;
+        match auth::validate(&state.tokens.access_token).await? {
+            Some(validation)
+                if std::time::Duration::from_secs(validation.expires_in) > REFRESH_MARGIN =>
+            {
+                state.expires_at = std::time::Instant::now()
+                    + std::time::Duration::from_secs(validation.expires_in);
+                Ok(())
+            }
+            _ => self.refresh_locked(&mut state).await,
+        }
+    }
+}
+
+#[async_trait]
+impl LoginCredentials for Credentials {
+    type Error = eyre::Report;
+    async fn get_credentials(&self) -> eyre::Result<CredentialsPair> {
+        Ok(CredentialsPair {
+            login: self.inner.login.clone(),
+            token: Some(self.access_token().await?),
+        })
+    }
+}
+
+#[async_trait]
+impl helix::Token for Credentials {
+    async fn access_token(&self) -> eyre::Result<String> {
+        Credentials::access_token(self).await
+    }
+    async fn refresh(&self) -> eyre::Result<String> {
+        Credentials::refresh(self).await
+    }
+}
@@ -0,0 +1,355 @@
+use super::*;
+
+use eyre::WrapErr;
+
+/// Event types we subscribe to with their versions and conditions
+fn subscriptions(
+    broadcaster_id: &str,
+    user_id: &str,
+    follows: bool,
+) -> Vec<(&'static str, &'static str, serde_json::Value)> {
+    let mut subscriptions = Vec::new();
+    // Only the broadcaster can see their redemptions
+    if broadcaster_id == user_id {
+        subscriptions.push((
+            "channel.channel_points_custom_reward_redemption.add",
+            "1",
+            serde_json::json!({ "broadcaster_user_id": broadcaster_id }),
+        ));
+    }
+    // Only moderators can see followers
+    if follows {
+        subscriptions.push((
+            "channel.follow",
+            "2",
+            serde_json::json!({
+                "broadcaster_user_id": broadcaster_id,
+                "moderator_user_id": user_id,
+            }),
+        ));
+    }
+    subscriptions
+}
+
+/// How long to wait for the welcome message, afterwards the server tells us the keepalive timeout
+const WELCOME_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// Reconnect delay doubles after every failed attempt, starting with this
+const MIN_RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// <https://dev.twitch.tv/docs/eventsub/websocket-reference/>
+#[derive(Deserialize)]
+struct Frame {
+    metadata: Metadata,
+    #[serde(default)]
+    payload: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    message_type: String,
+    subscription_type: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SessionPayload {
+    session: Session,
+}
+
+#[derive(Deserialize)]
+struct Session {
+    id: String,
+    keepalive_timeout_seconds: Option<u64>,
+    reconnect_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NotificationPayload<T> {
+    event: T,
+}
+
+#[derive(Deserialize)]
+struct RedemptionEvent {
+    broadcaster_user_login: String,
+    id: String,
+    user_name: String,
+    user_input: String,
+    reward: RedemptionReward,
+}
+
+#[derive(Deserialize)]
+struct RedemptionReward {
+    id: String,
+    title: String,
+    cost: u64,
+}
+
+#[derive(Deserialize)]
+struct FollowEvent {
+    broadcaster_user_login: String,
+    user_name: String,
+}
+
+/// Keep listening to EventSub notifications, reconnecting with backoff whenever something fails.
+/// Only returns once nobody is receiving the messages anymore.
+/// Must not be called from async code.
+pub fn run(
+    endpoints: &Endpoints,
+    credentials: &Credentials,
+    channels: &[String],
+    follows: bool,
+    sender: &UnboundedSender<Message>,
+) {
+    let mut delay = MIN_RECONNECT_DELAY;
+    let mut attempt: u32 = 0;
+    while !sender.is_closed() {
+        attempt += 1;
+        let mut connected = false;
+        let result = block_on(listen(
+            endpoints,
+            credentials,
+            channels,
+            follows,
+            sender,
+            &mut connected,
+        ));
+        // Twitch could also have been down for a long time, but if we got in once the next failure is new
+        if connected {
+            attempt = 1;
+            delay = MIN_RECONNECT_DELAY;
+        }
+        let error = match result {
+            Ok(()) => eyre::eyre!("EventSub listener stopped"),
+            Err(e) => e,
+        };
+        log::error!("EventSub failed (attempt {attempt}, retrying in {delay:?}): {error:#}");
+        let _ = sender.send(Message::EventStreamHealth {
+            session: credentials.login().to_owned(),
+            connected: false,
+            error: Some(format!("{error:#}")),
+        });
+        std::thread::sleep(delay);
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Listen until the connection is lost
+async fn listen(
+    endpoints: &Endpoints,
+    credentials: &Credentials,
+    channels: &[String],
+    follows: bool,
+    sender: &UnboundedSender<Message>,
+    connected: &mut bool,
+) -> eyre::Result<()> {
+    let helix = helix::Client::new(
+        &endpoints.helix,
+        credentials.client_id(),
+        credentials.clone(),
+    );
+    let logins: Vec<&str> = channels.iter().map(|channel| channel.as_str()).collect();
+    let broadcasters = helix
+        .users(&logins)
+        .await
+        .wrap_err("Failed to get the channel user ids")?;
+    let user_id = helix
+        .user_id(None)
+        .await
+        .wrap_err("Failed to get our own user id")?;
+    let subscriptions: Vec<_> = broadcasters
+        .iter()
+        .flat_map(|broadcaster| subscriptions(&broadcaster.id, &user_id, follows))
+        .collect();
+    let mut ws = connect(&endpoints.eventsub_websocket).await?;
+    let mut old = None;
+    let mut subscribe = true;
+    loop {
+        let url = session(
+            &mut ws,
+            &mut old,
+            credentials.login(),
+            &helix,
+            &subscriptions,
+            subscribe,
+            sender,
+            connected,
+        )
+        .await?;
+        // Twitch keeps sending events to the old connection until the new one is welcomed
+        old = Some(std::mem::replace(&mut ws, connect(&url).await?));
+        // Subscriptions are moved to the new session
+        subscribe = false;
+    }
+}
+
+type Socket = websocket_lite::AsyncClient<
+    Box<dyn websocket_lite::AsyncNetworkStream + Sync + Send + Unpin + 'static>,
+>;
+
+async fn connect(url: &str) -> eyre::Result<Socket> {
+    log::debug!("Connecting to EventSub at {url}");
+    websocket_lite::ClientBuilder::new(url)?
+        .async_connect()
+        .await
+        .map_err(|e| eyre::eyre!("Failed to connect to EventSub: {e}"))
+}
+
+/// Next text message, answering pings on the way
+async fn next_frame(ws: &mut Socket, timeout: std::time::Duration) -> eyre::Result<Frame> {
+    loop {
+        let message = match tokio::time::timeout(timeout, ws.next()).await {
+            Ok(Some(message)) => message.map_err(|e| eyre::eyre!("EventSub error: {e}"))?,
+            Ok(None) => eyre::bail!("EventSub connection closed"),
+            Err(_) => eyre::bail!("Nothing from EventSub for {timeout:?}"),
+        };
+        match message.opcode() {
+            websocket_lite::Opcode::Text => {}
+            websocket_lite::Opcode::Ping => {
+                ws.send(websocket_lite::Message::pong(message.into_data()))
+                    .await
+                    .map_err(|e| eyre::eyre!("EventSub error: {e}"))?;
+                continue;
+            }
+            websocket_lite::Opcode::Close => eyre::bail!("EventSub closed the connection"),
+            _ => continue,
+        }
+        let Some(text) = message.as_text() else {
+            eyre::bail!("EventSub sent invalid utf-8");
+        };
+        return serde_json::from_str(text).wrap_err("Unexpected EventSub message");
+    }
+}
+
+/// Handle a single websocket session, returns the url we were asked to reconnect to.
+/// Until the session is welcomed, events still coming from the `old` one are handled too.
+#[allow(clippy::too_many_arguments)]
+async fn session(
+    ws: &mut Socket,
+    old: &mut Option<Socket>,
+    login: &str,
+    helix: &helix::Client,
+    subscriptions: &[(&str, &str, serde_json::Value)],
+    subscribe: bool,
+    sender: &UnboundedSender<Message>,
+    connected: &mut bool,
+) -> eyre::Result<String> {
+    use futures::future::Either;
+    let mut timeout = WELCOME_TIMEOUT;
+    loop {
+        let next = match old.as_mut() {
+            Some(old_ws) => match futures::future::select(
+                Box::pin(next_frame(ws, timeout)),
+                Box::pin(next_frame(old_ws, timeout)),
+            )
+            .await
+            {
+                Either::Left((frame, _)) => Either::Left(frame?),
+                Either::Right((frame, _)) => Either::Right(frame),
+            },
+            None => Either::Left(next_frame(ws, timeout).await?),
+        };
+        let frame = match next {
+            Either::Left(frame) => frame,
+            Either::Right(Ok(frame)) => {
+                if frame.metadata.message_type == "notification" {
+                    notification(frame, sender);
+                }
+                continue;
+            }
+            Either::Right(Err(e)) => {
+                log::debug!("Old EventSub connection ended: {e:#}");
+                *old = None;
+                continue;
+            }
+        };
+        match frame.metadata.message_type.as_str() {
+            "session_welcome" => {
+                let SessionPayload { session } = serde_json::from_value(frame.payload)?;
+                log::info!("Connected to EventSub");
+                // Nothing is lost by closing the old connection now
+                *old = None;
+                if let Some(seconds) = session.keepalive_timeout_seconds {
+                    // Some slack for the network
+                    timeout = std::time::Duration::from_secs(seconds + 5);
+                }
+                if subscribe {
+                    for (subscription_type, version, condition) in subscriptions {
+                        // Missing scopes for one event type should not stop the others
+                        if let Err(e) = helix
+                            .create_eventsub_subscription(
+                                subscription_type,
+                                version,
+                                condition.clone(),
+                                &session.id,
+                            )
+                            .await
+                        {
+                            log::error!("Failed to subscribe to {subscription_type}: {e:#}");
+                        }
+                    }
+                }
+                *connected = true;
+                let _ = sender.send(Message::EventStreamHealth {
+                    session: login.to_owned(),
+                    connected: true,
+                    error: None,
+                });
+            }
+            "session_keepalive" => {}
+            "session_reconnect" => {
+                let SessionPayload { session } = serde_json::from_value(frame.payload)?;
+                log::info!("EventSub asked us to reconnect");
+                return session
+                    .reconnect_url
+                    .ok_or_else(|| eyre::eyre!("EventSub reconnect without url"));
+            }
+            "notification" => notification(frame, sender),
+            "revocation" => log::warn!("EventSub subscription revoked: {}", frame.payload),
+            message_type => log::debug!("Unexpected EventSub message {message_type:?}"),
+        }
+    }
+}
+
+fn notification(frame: Frame, sender: &UnboundedSender<Message>) {
+    let subscription_type = frame.metadata.subscription_type.unwrap_or_default();
+    // One weird event should not cost us the connection
+    if let Err(e) = handle_notification(&subscription_type, frame.payload, sender) {
+        log::error!("Failed to handle {subscription_type} notification: {e:#}");
+    }
+}
+
+fn handle_notification(
+    subscription_type: &str,
+    payload: serde_json::Value,
+    sender: &UnboundedSender<Message>,
+) -> eyre::Result<()> {
+    match subscription_type {
+        "channel.channel_points_custom_reward_redemption.add" => {
+            let NotificationPayload::<RedemptionEvent> { event } = serde_json::from_value(payload)?;
+            log::info!("{} redeemed {}", event.user_name, event.reward.title);
+            sender
+                .send(Message::RewardRedemption {
+                    channel: event.broadcaster_user_login,
+                    id: event.id,
+                    name: event.user_name,
+                    reward: event.reward.title,
+                    reward_id: event.reward.id,
+                    cost: event.reward.cost,
+                    input: Some(event.user_input).filter(|input| !input.is_empty()),
+                })
+                .map_err(|_| eyre::eyre!("Nobody is listening to events anymore"))?;
+        }
+        "channel.follow" => {
+            let NotificationPayload::<FollowEvent> { event } = serde_json::from_value(payload)?;
+            log::info!("{} followed", event.user_name);
+            sender
+                .send(Message::Follow {
+                    channel: event.broadcaster_user_login,
+                    name: event.user_name,
+                })
+                .map_err(|_| eyre::eyre!("Nobody is listening to events anymore"))?;
+        }
+        _ => log::debug!("Unexpected EventSub notification {subscription_type:?}"),
+    }
+    Ok(())
+}
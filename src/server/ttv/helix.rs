@@ -0,0 +1,505 @@
+use super::*;
+
+/// Where requests get the access token from
+#[async_trait]
+pub trait Token: Send + Sync {
+    async fn access_token(&self) -> eyre::Result<String>;
+    /// Get a new token because twitch rejected the current one
+    async fn refresh(&self) -> eyre::Result<String>;
+}
+
+/// Token that can not be refreshed, requests just fail once it expires
+pub struct StaticToken(pub String);
+
+#[async_trait]
+impl Token for StaticToken {
+    async fn access_token(&self) -> eyre::Result<String> {
+        Ok(self.0.clone())
+    }
+    async fn refresh(&self) -> eyre::Result<String> {
+        eyre::bail!("Token can not be refreshed")
+    }
+}
+
+/// Page size for paginated requests, the maximum twitch allows
+const PAGE_SIZE: &str = "100";
+
+#[derive(Debug)]
+pub enum Error {
+    Http(reqwest::Error),
+    /// Twitch answered with an error
+    Api {
+        status: reqwest::StatusCode,
+        message: String,
+    },
+    /// Could not get a (new) token
+    Token(eyre::Report),
+    /// Successful response without the thing we asked for
+    NotFound,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http(e) => write!(f, "Helix request failed: {e}"),
+            Self::Api { status, message } => write!(f, "Helix error {status}: {message}"),
+            Self::Token(e) => write!(f, "Failed to get a token: {e:#}"),
+            Self::NotFound => write!(f, "Not found"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Deserialize)]
+struct Data<T> {
+    data: Vec<T>,
+    #[serde(default)]
+    pagination: Pagination,
+}
+
+#[derive(Deserialize, Default)]
+struct Pagination {
+    cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    message: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct User {
+    pub id: String,
+    pub login: String,
+    pub display_name: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Channel {
+    pub broadcaster_id: String,
+    pub broadcaster_login: String,
+    pub broadcaster_name: String,
+    pub game_name: String,
+    pub title: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Chatter {
+    pub user_id: String,
+    pub user_login: String,
+    pub user_name: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CustomReward {
+    pub id: String,
+    pub title: String,
+    pub cost: u64,
+    pub prompt: String,
+    pub is_paused: bool,
+}
+
+/// Fields of a custom reward to create or change, missing ones are left as they are
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct CustomRewardSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_user_input_required: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_global_cooldown_enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub global_cooldown_seconds: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_paused: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct BanRequest<'a> {
+    data: BanData<'a>,
+}
+
+#[derive(Serialize)]
+struct BanData<'a> {
+    user_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<u64>,
+    reason: &'a str,
+}
+
+/// Typed client for the parts of <https://dev.twitch.tv/docs/api/reference> we use
+pub struct Client {
+    base_url: String,
+    client_id: String,
+    token: Box<dyn Token>,
+    http: reqwest::Client,
+}
+
+impl Client {
+    /// Base url is normally <https://api.twitch.tv/helix>, but can point to a mock server
+    pub fn new(base_url: &str, client_id: &str, token: impl Token + 'static) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            client_id: client_id.to_owned(),
+            token: Box::new(token),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.http
+            .request(method, format!("{}/{path}", self.base_url))
+            .header("Client-Id", &self.client_id)
+    }
+
+    /// Send the request, getting a new token and trying again if the current one is rejected
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let retry = request.try_clone();
+        let access_token = self.token.access_token().await.map_err(Error::Token)?;
+        let mut response = request.bearer_auth(access_token).send().await?;
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            if let Some(retry) = retry {
+                log::debug!("Helix token rejected, refreshing");
+                let access_token = self.token.refresh().await.map_err(Error::Token)?;
+                response = retry.bearer_auth(access_token).send().await?;
+            }
+        }
+        let status = response.status();
+        if !status.is_success() {
+            let message = match response.json::<ErrorResponse>().await {
+                Ok(error) => error.message,
+                Err(_) => String::new(),
+            };
+            return Err(Error::Api { status, message });
+        }
+        Ok(response)
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<Vec<T>> {
+        let data: Data<T> = self
+            .send(self.request(reqwest::Method::GET, path).query(query))
+            .await?
+            .json()
+            .await?;
+        Ok(data.data)
+    }
+
+    /// Follow the pagination cursors to get everything
+    async fn get_all<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<Vec<T>> {
+        let mut result = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut request = self
+                .request(reqwest::Method::GET, path)
+                .query(query)
+                .query(&[("first", PAGE_SIZE)]);
+            if let Some(cursor) = &cursor {
+                request = request.query(&[("after", cursor)]);
+            }
+            let page: Data<T> = self.send(request).await?.json().await?;
+            let empty = page.data.is_empty();
+            result.extend(page.data);
+            cursor = page.pagination.cursor.filter(|cursor| !cursor.is_empty());
+            if cursor.is_none() || empty {
+                return Ok(result);
+            }
+        }
+    }
+
+    pub async fn users(&self, logins: &[&str]) -> Result<Vec<User>> {
+        let query: Vec<(&str, &str)> = logins.iter().map(|&login| ("login", login)).collect();
+        self.get("users", &query).await
+    }
+
+    /// The user the token belongs to
+    pub async fn current_user(&self) -> Result<User> {
+        self.get("users", &[]).await?.pop().ok_or(Error::NotFound)
+    }
+
+    /// Id of the user by login, or of the user the token belongs to
+    pub async fn user_id(&self, login: Option<&str>) -> eyre::Result<String> {
+        let user = match login {
+            Some(login) => self.users(&[login]).await?.pop(),
+            None => Some(self.current_user().await?),
+        };
+        let user = user.ok_or_else(|| eyre::eyre!("User {login:?} not found"))?;
+        Ok(user.id)
+    }
+
+    pub async fn channel(&self, broadcaster_id: &str) -> Result<Channel> {
+        self.get("channels", &[("broadcaster_id", broadcaster_id)])
+            .await?
+            .pop()
+            .ok_or(Error::NotFound)
+    }
+
+    /// Needs `moderator:read:chatters`
+    pub async fn chatters(&self, broadcaster_id: &str, moderator_id: &str) -> Result<Vec<Chatter>> {
+        self.get_all(
+            "chat/chatters",
+            &[
+                ("broadcaster_id", broadcaster_id),
+                ("moderator_id", moderator_id),
+            ],
+        )
+        .await
+    }
+
+    /// Ban the user, or time them out if there is a duration (in seconds).
+    /// Needs `moderator:manage:banned_users`
+    pub async fn ban(
+        &self,
+        broadcaster_id: &str,
+        moderator_id: &str,
+        user_id: &str,
+        duration: Option<u64>,
+        reason: &str,
+    ) -> Result<()> {
+        self.send(
+            self.request(reqwest::Method::POST, "moderation/bans")
+                .query(&[
+                    ("broadcaster_id", broadcaster_id),
+                    ("moderator_id", moderator_id),
+                ])
+                .json(&BanRequest {
+                    data: BanData {
+                        user_id,
+                        duration,
+                        reason,
+                    },
+                }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Needs `moderator:manage:banned_users`
+    pub async fn unban(
+        &self,
+        broadcaster_id: &str,
+        moderator_id: &str,
+        user_id: &str,
+    ) -> Result<()> {
+        self.send(
+            self.request(reqwest::Method::DELETE, "moderation/bans")
+                .query(&[
+                    ("broadcaster_id", broadcaster_id),
+                    ("moderator_id", moderator_id),
+                    ("user_id", user_id),
+                ]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Needs `moderator:manage:chat_messages`
+    pub async fn delete_message(
+        &self,
+        broadcaster_id: &str,
+        moderator_id: &str,
+        message_id: &str,
+    ) -> Result<()> {
+        self.send(
+            self.request(reqwest::Method::DELETE, "moderation/chat")
+                .query(&[
+                    ("broadcaster_id", broadcaster_id),
+                    ("moderator_id", moderator_id),
+                    ("message_id", message_id),
+                ]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// All custom rewards of the channel, including ones made in the dashboard
+    pub async fn custom_rewards(&self, broadcaster_id: &str) -> Result<Vec<CustomReward>> {
+        self.get(
+            "channel_points/custom_rewards",
+            &[("broadcaster_id", broadcaster_id)],
+        )
+        .await
+    }
+
+    /// Custom rewards created by our client id, others can not be changed by us
+    pub async fn manageable_rewards(&self, broadcaster_id: &str) -> Result<Vec<CustomReward>> {
+        self.get(
+            "channel_points/custom_rewards",
+            &[
+                ("broadcaster_id", broadcaster_id),
+                ("only_manageable_rewards", "true"),
+            ],
+        )
+        .await
+    }
+
+    pub async fn create_reward(
+        &self,
+        broadcaster_id: &str,
+        settings: &CustomRewardSettings,
+    ) -> Result<CustomReward> {
+        let rewards: Data<CustomReward> = self
+            .send(
+                self.request(reqwest::Method::POST, "channel_points/custom_rewards")
+                    .query(&[("broadcaster_id", broadcaster_id)])
+                    .json(settings),
+            )
+            .await?
+            .json()
+            .await?;
+        rewards.data.into_iter().next().ok_or(Error::NotFound)
+    }
+
+    pub async fn update_reward(
+        &self,
+        broadcaster_id: &str,
+        id: &str,
+        settings: &CustomRewardSettings,
+    ) -> Result<()> {
+        self.send(
+            self.request(reqwest::Method::PATCH, "channel_points/custom_rewards")
+                .query(&[("broadcaster_id", broadcaster_id), ("id", id)])
+                .json(settings),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Only works for rewards created by the same client id
+    pub async fn update_redemption_status(
+        &self,
+        broadcaster_id: &str,
+        reward_id: &str,
+        id: &str,
+        status: RedemptionStatus,
+    ) -> Result<()> {
+        let status = match status {
+            RedemptionStatus::Fulfilled => "FULFILLED",
+            RedemptionStatus::Canceled => "CANCELED",
+        };
+        self.send(
+            self.request(
+                reqwest::Method::PATCH,
+                "channel_points/custom_rewards/redemptions",
+            )
+            .query(&[
+                ("broadcaster_id", broadcaster_id),
+                ("reward_id", reward_id),
+                ("id", id),
+            ])
+            .json(&serde_json::json!({ "status": status })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Subscribe the EventSub websocket session to an event type
+    pub async fn create_eventsub_subscription(
+        &self,
+        subscription_type: &str,
+        version: &str,
+        condition: serde_json::Value,
+        session_id: &str,
+    ) -> Result<()> {
+        self.send(
+            self.request(reqwest::Method::POST, "eventsub/subscriptions")
+                .json(&serde_json::json!({
+                    "type": subscription_type,
+                    "version": version,
+                    "condition": condition,
+                    "transport": {
+                        "method": "websocket",
+                        "session_id": session_id,
+                    },
+                })),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Serves two pages of chatters, but only to requests with the refreshed token
+#[test]
+fn test_pagination_and_refresh() {
+    let addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = std::net::TcpListener::bind(addr).unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let service = hyper::service::make_service_fn(|_| async {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+                |request: hyper::Request<hyper::Body>| async move {
+                    let authorized = request.headers()["Authorization"] == "Bearer new";
+                    let query = request.uri().query().unwrap_or_default();
+                    let (status, body) = if !authorized {
+                        (
+                            401,
+                            r#"{"error":"Unauthorized","status":401,"message":"Invalid OAuth token"}"#,
+                        )
+                    } else if query.contains("after=next") {
+                        (
+                            200,
+                            r#"{"data":[{"user_id":"2","user_login":"b","user_name":"B"}],"pagination":{}}"#,
+                        )
+                    } else {
+                        (
+                            200,
+                            r#"{"data":[{"user_id":"1","user_login":"a","user_name":"A"}],"pagination":{"cursor":"next"}}"#,
+                        )
+                    };
+                    Ok::<_, std::convert::Infallible>(
+                        hyper::Response::builder()
+                            .status(status)
+                            .body(hyper::Body::from(body))
+                            .unwrap(),
+                    )
+                },
+            ))
+        });
+        block_on(async move {
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(service)
+                .await
+                .unwrap();
+        });
+    });
+    struct TestToken(Mutex<String>);
+    #[async_trait]
+    impl Token for TestToken {
+        async fn access_token(&self) -> eyre::Result<String> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+        async fn refresh(&self) -> eyre::Result<String> {
+            *self.0.lock().unwrap() = "new".to_owned();
+            self.access_token().await
+        }
+    }
+    let token = TestToken(Mutex::new("old".to_owned()));
+    let client = Client::new(&format!("http://{addr}"), "client", token);
+    let chatters = block_on(client.chatters("broadcaster", "moderator")).unwrap();
+    let logins: Vec<&str> = chatters
+        .iter()
+        .map(|chatter| chatter.user_login.as_str())
+        .collect();
+    assert_eq!(logins, ["a", "b"]);
+}
@@ -1,21 +1,61 @@
 use super::*;
 
 pub mod auth;
+mod credentials;
+pub mod eventsub;
+#[allow(dead_code)] // Covers more of the api than the bot uses so far
+pub mod helix;
 
+pub use credentials::Credentials;
 use reqwest::Url;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use twitch_irc::{
-    login::StaticLoginCredentials,
-    message::{PrivmsgMessage, ServerMessage},
+    message::{IRCMessage, PrivmsgMessage, ServerMessage, UserNoticeEvent, UserNoticeMessage},
     ClientConfig, SecureTCPTransport, TwitchIRCClient,
 };
 
 pub type IrcMessage = ServerMessage;
 
+/// Where to find twitch APIs, can be pointed to a local fake server for testing
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Endpoints {
+    pub helix: String,
+    pub eventsub_websocket: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self {
+            helix: "https://api.twitch.tv/helix".to_owned(),
+            eventsub_websocket: "wss://eventsub.wss.twitch.tv/ws".to_owned(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Message {
     Irc(ServerMessage),
-    RewardRedemption { name: String, reward: String },
+    RewardRedemption {
+        channel: String,
+        id: String,
+        name: String,
+        reward: String,
+        reward_id: String,
+        cost: u64,
+        input: Option<String>,
+    },
+    Follow {
+        channel: String,
+        name: String,
+    },
+    /// Whether we are receiving events (like channel point redemptions)
+    EventStreamHealth {
+        /// Login of the account the events are received as
+        session: String,
+        connected: bool,
+        error: Option<String>,
+    },
 }
 
 // Lets join thread on drop so we shutdown without missing anything
@@ -29,8 +69,16 @@ impl Drop for ThreadJoinHandle {
 }
 
 pub struct Client {
-    channel_login: String,
-    inner: TwitchIRCClient<SecureTCPTransport, StaticLoginCredentials>,
+    inner: TwitchIRCClient<SecureTCPTransport, Credentials>,
+    helix: helix::Client,
+    /// Clients authorized by the broadcasters themselves, for managing channel points
+    broadcaster_helix: HashMap<String, helix::Client>,
+    /// User ids of the channels we joined by their logins
+    broadcaster_ids: HashMap<String, String>,
+    /// Our own user id, we moderate the channel as this user
+    user_id: String,
+    /// Runtime of the irc thread, to run helix requests on
+    runtime: tokio::runtime::Handle,
     messages: UnboundedReceiver<Message>,
 
     // This should be dropped after TwitchIRCClient (so the order of fields is important here),
@@ -40,32 +88,81 @@ pub struct Client {
 }
 
 impl Client {
-    pub fn new(channel_login: &str, bot_login: &str) -> Self {
-        let channel_login = channel_login.to_owned();
+    /// Channels and the bot login must be lowercase, like twitch logins are
+    pub fn new(channels: &[String], bot_login: &str, endpoints: &Endpoints) -> Self {
+        let channels = channels.to_vec();
 
         let tokio_runtime = tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
             .unwrap();
 
-        let config = ClientConfig::new_simple(StaticLoginCredentials::new(
-            bot_login.to_owned(),
-            Some(
-                Secrets::init()
-                    .unwrap()
-                    .ttv_access_token(bot_login)
-                    .unwrap(),
-            ),
-        ));
+        // Shared by irc, EventSub and helix so that a refreshed token is used everywhere
+        let secrets = Secrets::init().unwrap();
+        let credentials = secrets
+            .ttv_credentials(bot_login, &Account::of(bot_login, bot_login, &channels))
+            .unwrap();
+        tokio_runtime.spawn(credentials.clone().keep_fresh());
+        // Only the broadcaster can manage and see redemptions of their channel points
+        let mut broadcaster_credentials = HashMap::new();
+        for channel in &channels {
+            if channel == bot_login {
+                broadcaster_credentials.insert(channel.clone(), credentials.clone());
+                continue;
+            }
+            match secrets.ttv_credentials(channel, &[Account::Broadcaster]) {
+                Ok(credentials) => {
+                    tokio_runtime.spawn(credentials.clone().keep_fresh());
+                    broadcaster_credentials.insert(channel.clone(), credentials);
+                }
+                Err(e) => log::warn!("No channel points in {channel:?}: {e:#}"),
+            }
+        }
+        let broadcaster_helix = broadcaster_credentials
+            .iter()
+            .map(|(channel, credentials)| {
+                (
+                    channel.clone(),
+                    helix::Client::new(
+                        &endpoints.helix,
+                        credentials.client_id(),
+                        credentials.clone(),
+                    ),
+                )
+            })
+            .collect();
+        let helix = helix::Client::new(
+            &endpoints.helix,
+            credentials.client_id(),
+            credentials.clone(),
+        );
+        let logins: Vec<&str> = channels.iter().map(|channel| channel.as_str()).collect();
+        let broadcaster_ids: HashMap<String, String> = tokio_runtime
+            .block_on(helix.users(&logins))
+            .unwrap()
+            .into_iter()
+            .map(|user| (user.login, user.id))
+            .collect();
+        for channel in &channels {
+            assert!(
+                broadcaster_ids.contains_key(channel),
+                "Channel {channel:?} not found",
+            );
+        }
+        let user_id = tokio_runtime.block_on(helix.user_id(None)).unwrap();
+        let runtime = tokio_runtime.handle().clone();
+
+        let config = ClientConfig::new_simple(credentials.clone());
         log::debug!("Connecting to ttv irc");
-        let (mut incoming_messages, client) = tokio_runtime.block_on(async {
-            TwitchIRCClient::<SecureTCPTransport, StaticLoginCredentials>::new(config)
-        });
+        let (mut incoming_messages, client) = tokio_runtime
+            .block_on(async { TwitchIRCClient::<SecureTCPTransport, Credentials>::new(config) });
         log::debug!("Connected to ttv irc");
 
         let (messages_sender, messages_receiver) = tokio::sync::mpsc::unbounded_channel();
 
-        client.join(channel_login.clone()).unwrap();
+        for channel in &channels {
+            client.join(channel.clone()).unwrap();
+        }
         let async_thread = {
             let messages_sender = messages_sender.clone();
             async move {
@@ -87,26 +184,36 @@ impl Client {
             log::debug!("Ttv client thread stopped");
         });
 
-        std::thread::spawn({
-            let channel_login = channel_login.clone();
-            let bot_login = bot_login.to_owned();
-            move || {
-                log::debug!("PubSub thread started");
-                pubsub(
-                    &Secrets::init()
-                        .unwrap()
-                        .ttv_access_token(bot_login)
-                        .unwrap(),
-                    &channel_login,
-                    messages_sender,
-                );
-                log::debug!("PubSub thread stopped");
+        // The bot listens for follows everywhere, broadcasters for their own redemptions
+        let mut sessions = vec![(credentials, channels.clone(), true)];
+        for (channel, credentials) in broadcaster_credentials {
+            if channel != bot_login {
+                sessions.push((credentials, vec![channel], false));
             }
-        });
+        }
+        for (credentials, channels, follows) in sessions {
+            let endpoints = endpoints.clone();
+            let messages_sender = messages_sender.clone();
+            std::thread::spawn(move || {
+                log::debug!("EventSub thread started");
+                eventsub::run(
+                    &endpoints,
+                    &credentials,
+                    &channels,
+                    follows,
+                    &messages_sender,
+                );
+                log::debug!("EventSub thread stopped");
+            });
+        }
 
         Self {
-            channel_login: channel_login.clone(),
             inner: client,
+            helix,
+            broadcaster_helix,
+            broadcaster_ids,
+            user_id,
+            runtime,
             messages: messages_receiver,
             thread: ThreadJoinHandle {
                 inner: Some(thread),
@@ -122,148 +229,190 @@ impl Client {
         self.messages.try_recv().ok()
     }
 
-    pub fn say(&self, message: &str, reply_to: Option<String>) {
+    fn broadcaster_id(&self, channel: &str) -> eyre::Result<&str> {
+        match self.broadcaster_ids.get(channel) {
+            Some(id) => Ok(id),
+            None => eyre::bail!("Not in channel {channel:?}"),
+        }
+    }
+
+    fn broadcaster_helix(&self, channel: &str) -> eyre::Result<&helix::Client> {
+        match self.broadcaster_helix.get(channel) {
+            Some(helix) => Ok(helix),
+            None => eyre::bail!("{channel:?} did not authorize us to manage channel points"),
+        }
+    }
+
+    pub fn say(&self, channel: &str, message: &str, reply_to: Option<String>) -> eyre::Result<()> {
+        self.broadcaster_id(channel)?;
         futures::executor::block_on(self.inner.say_in_response(
-            self.channel_login.clone(),
+            channel.to_owned(),
             message.to_owned(),
             reply_to,
         ))
-        .unwrap();
+        .map_err(|e| eyre::eyre!("Failed to send a chat message: {e}"))
+    }
+
+    /// Create or update the declared rewards, returns their keys mapped to reward ids
+    pub fn sync_rewards(
+        &self,
+        channel: &str,
+        rewards: &HashMap<String, rewards::Reward>,
+        saved: &HashMap<String, String>,
+    ) -> eyre::Result<HashMap<String, String>> {
+        let broadcaster_id = self.broadcaster_id(channel)?;
+        self.runtime.block_on(rewards::sync(
+            self.broadcaster_helix(channel)?,
+            broadcaster_id,
+            rewards,
+            saved,
+        ))
+    }
+
+    pub fn delete_message(&self, channel: &str, id: &str) -> eyre::Result<()> {
+        Ok(self.runtime.block_on(self.helix.delete_message(
+            self.broadcaster_id(channel)?,
+            &self.user_id,
+            id,
+        ))?)
+    }
+
+    /// Ban the user, or time them out for `duration` seconds
+    pub fn ban(
+        &self,
+        channel: &str,
+        login: &str,
+        duration: Option<u64>,
+        reason: &str,
+    ) -> eyre::Result<()> {
+        let broadcaster_id = self.broadcaster_id(channel)?;
+        self.runtime.block_on(async {
+            let user_id = self.helix.user_id(Some(login)).await?;
+            self.helix
+                .ban(broadcaster_id, &self.user_id, &user_id, duration, reason)
+                .await?;
+            Ok(())
+        })
+    }
+
+    pub fn update_redemption(
+        &self,
+        channel: &str,
+        reward_id: &str,
+        id: &str,
+        status: RedemptionStatus,
+    ) -> eyre::Result<()> {
+        Ok(self
+            .runtime
+            .block_on(self.broadcaster_helix(channel)?.update_redemption_status(
+                self.broadcaster_id(channel)?,
+                reward_id,
+                id,
+                status,
+            ))?)
     }
 }
 
-fn pubsub(access_token: &str, channel_login: &str, sender: UnboundedSender<Message>) {
-    let secrets = Secrets::init().unwrap();
-    let tokio_runtime = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap();
-    let user_id = tokio_runtime.block_on(async {
-        let json = reqwest::Client::new()
-            .get("https://api.twitch.tv/helix/users")
-            .query(&[("login", channel_login)])
-            .header("Authorization", format!("Bearer {}", access_token))
-            .header("Client-ID", &secrets.config.ttv.client_id)
-            .send()
-            .await
-            .unwrap()
-            .text()
-            .await
-            .unwrap();
-        serde_json::from_str::<serde_json::Value>(&json)
-            .unwrap()
-            .as_object()
-            .unwrap()
-            .get("data")
-            .unwrap()
-            .as_array()
-            .unwrap()[0]
-            .as_object()
-            .unwrap()
-            .get("id")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .to_owned()
-    });
+/// Value of an irc tag, empty values are treated as missing
+pub fn tag<'a>(message: &'a IRCMessage, name: &str) -> Option<&'a str> {
+    message
+        .tags
+        .0
+        .get(name)
+        .and_then(|value| value.as_deref())
+        .filter(|value| !value.is_empty())
+}
 
-    block_on(async move {
-        let mut ws = websocket_lite::ClientBuilder::new("wss://pubsub-edge.twitch.tv")
-            .unwrap()
-            .async_connect()
-            .await
-            .unwrap();
-        let request = serde_json::json!({
-            "type": "LISTEN",
-            "nonce": "kekw",
-            "data": {
-                "topics": [format!("community-points-channel-v1.{}", user_id)],
-                "auth_token": access_token,
-            }
-        });
-        ws.send(websocket_lite::Message::text(
-            serde_json::to_string(&request).unwrap(),
-        ))
-        .await
-        .unwrap();
-        let mut timer = Timer::new();
-        loop {
-            if timer.elapsed().as_secs_f64() > 60.0 {
-                log::debug!("Sending ping to pubsub");
-                ws.send(websocket_lite::Message::text(r#"{"type": "PING"}"#))
-                    .await
-                    .unwrap();
-                timer.tick();
-            }
-            let message = {
-                let message =
-                    tokio::time::timeout(std::time::Duration::from_secs(10), ws.next()).await;
-                match message {
-                    Ok(message) => message,
-                    Err(_) => {
-                        continue;
-                    }
-                }
-            };
-            let message = message.unwrap().unwrap();
-            log::debug!("{:?}", message);
-            let message = serde_json::from_str::<serde_json::Value>(message.as_text().unwrap())
-                .unwrap()
-                .as_object()
-                .unwrap()
-                .clone();
-            if message.get("type").unwrap() == "MESSAGE" {
-                let message = message
-                    .get("data")
-                    .unwrap()
-                    .as_object()
-                    .unwrap()
-                    .get("message")
-                    .unwrap()
-                    .as_str()
-                    .unwrap()
-                    .to_owned();
-                let message = serde_json::from_str::<serde_json::Value>(&message)
-                    .unwrap()
-                    .as_object()
-                    .unwrap()
-                    .clone();
-                if message.get("type").unwrap().as_str().unwrap() == "reward-redeemed" {
-                    let data = message
-                        .get("data")
-                        .unwrap()
-                        .as_object()
-                        .unwrap()
-                        .get("redemption")
-                        .unwrap()
-                        .as_object()
-                        .unwrap();
-                    let name = data
-                        .get("user")
-                        .unwrap()
-                        .as_object()
-                        .unwrap()
-                        .get("display_name")
-                        .unwrap()
-                        .as_str()
-                        .unwrap()
-                        .to_owned();
-                    let reward = data
-                        .get("reward")
-                        .unwrap()
-                        .as_object()
-                        .unwrap()
-                        .get("title")
-                        .unwrap()
-                        .as_str()
-                        .unwrap()
-                        .to_owned();
-                    log::info!("{} redeemed {}", name, reward);
-                    sender
-                        .send(Message::RewardRedemption { name, reward })
-                        .unwrap();
-                }
-            }
-        }
-    });
+pub fn chat_user(message: &PrivmsgMessage) -> ChatUser {
+    ChatUser {
+        id: message.sender.id.clone(),
+        login: message.sender.login.clone(),
+        display_name: message.sender.name.clone(),
+        color: message.name_color.map(|color| {
+            Rgba::opaque(
+                color.r as f32 / 255.0,
+                color.g as f32 / 255.0,
+                color.b as f32 / 255.0,
+            )
+        }),
+        badges: message
+            .badges
+            .iter()
+            .map(|badge| Badge {
+                name: badge.name.clone(),
+                version: badge.version.clone(),
+            })
+            .collect(),
+        role: Role::Everyone,
+    }
+}
+
+pub fn emotes(message: &PrivmsgMessage) -> Vec<Emote> {
+    message
+        .emotes
+        .iter()
+        .map(|emote| Emote {
+            id: emote.id.clone(),
+            code: emote.code.clone(),
+            char_range: emote.char_range.clone(),
+        })
+        .collect()
+}
+
+fn subscription_tier(sub_plan: &str) -> SubscriptionTier {
+    match sub_plan {
+        "Prime" => SubscriptionTier::Prime,
+        "2000" => SubscriptionTier::Tier2,
+        "3000" => SubscriptionTier::Tier3,
+        _ => SubscriptionTier::Tier1,
+    }
+}
+
+/// Subs, gifts and raids are announced in chat as USERNOTICE
+pub fn user_notice(message: &UserNoticeMessage) -> Option<crate::ServerMessage> {
+    let channel = message.channel_login.clone();
+    let name = message.sender.name.clone();
+    Some(match &message.event {
+        UserNoticeEvent::SubOrResub {
+            cumulative_months,
+            sub_plan,
+            ..
+        } => crate::ServerMessage::Subscription {
+            channel,
+            name,
+            tier: subscription_tier(sub_plan),
+            months: *cumulative_months,
+            message: message.message_text.clone(),
+        },
+        UserNoticeEvent::SubGift {
+            is_sender_anonymous,
+            recipient,
+            sub_plan,
+            ..
+        } => crate::ServerMessage::GiftSubscription {
+            channel,
+            gifter: (!is_sender_anonymous).then_some(name),
+            recipient: recipient.name.clone(),
+            tier: subscription_tier(sub_plan),
+        },
+        UserNoticeEvent::Raid { viewer_count, .. } => crate::ServerMessage::Raid {
+            channel,
+            name,
+            viewers: *viewer_count,
+        },
+        _ => return None,
+    })
+}
+
+pub fn reply_parent(message: &PrivmsgMessage) -> Option<ReplyParent> {
+    let get = |name: &str| tag(&message.source, name).map(|value| value.to_owned());
+    Some(ReplyParent {
+        id: MessageId {
+            channel: message.channel_login.clone(),
+            id: get("reply-parent-msg-id")?,
+        },
+        user_login: get("reply-parent-user-login")?,
+        display_name: get("reply-parent-display-name")?,
+        message: get("reply-parent-msg-body").unwrap_or_default(),
+    })
 }
@@ -20,9 +20,8 @@ pub fn read_file(path: impl AsRef<std::path::Path>) -> eyre::Result<String> {
     Ok(result)
 }
 
-/// Run a local server and wait for an http request, and return the uri
-pub async fn wait_for_request_uri() -> eyre::Result<Url> {
-    let addr: std::net::SocketAddr = "127.0.0.1:3000".parse().unwrap();
+/// Run a local server and wait for a single http request, returns its uri joined to the `base` url
+pub async fn wait_for_request_uri(addr: std::net::SocketAddr, base: &Url) -> eyre::Result<Url> {
     log::debug!("Listening {}", addr);
     let listener = tokio::net::TcpListener::bind(addr).await?;
     // We just wait for the first connection
@@ -53,7 +52,5 @@ pub async fn wait_for_request_uri() -> eyre::Result<Url> {
         .recv()
         .await
         .ok_or_else(|| eyre::Report::msg("Failed to wait for the request"))?;
-    Ok(Url::parse("http://localhost:3000")
-        .unwrap()
-        .join(&uri.path_and_query().unwrap().as_str())?)
+    Ok(base.join(uri.path_and_query().unwrap().as_str())?)
 }